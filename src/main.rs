@@ -1,565 +1,9008 @@
 use async_process::Command;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use encoding_rs::{Encoding, UTF_8};
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use fs2::FileExt;
+use futures_util::TryStreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use hmac::{Hmac, Mac};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder, TEXT_FORMAT,
+};
 use regex::Regex;
-use serde::{de::Visitor, Deserialize, Deserializer};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
     fmt::Display,
     future::Future,
-    net::SocketAddr,
+    io::Write,
+    net::{Ipv4Addr, SocketAddr},
     path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
+use subtle::ConstantTimeEq;
 use tera::{Context, Tera};
+use tokio::sync::Semaphore;
 use url::Url;
 use warp::{
-    filters::path::FullPath,
-    http::{Response, StatusCode},
-    reject::Rejection,
-    Filter,
+    filters::body::BodyDeserializeError,
+    filters::{multipart::FormData, path::FullPath, BoxedFilter},
+    http::{
+        header::{
+            HeaderName, ACCEPT, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING,
+            CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+            RETRY_AFTER, SET_COOKIE, VARY,
+        },
+        HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri,
+    },
+    hyper::{
+        body::Buf,
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn, Service},
+    },
+    reject::{PayloadTooLarge, Rejection, UnsupportedMediaType},
+    Filter, Reply,
 };
 
 #[derive(Deserialize)]
 struct Config {
-    bind: SocketAddr,
+    bind: Option<SocketAddr>,
+    bind_unix: Option<PathBuf>,
+    bind_unix_mode: Option<u32>,
+    #[serde(default = "default_header_read_timeout_secs")]
+    header_read_timeout_secs: u64,
+    #[serde(default = "default_request_timeout_secs")]
+    request_timeout_secs: u64,
+    tcp_keepalive_secs: Option<u64>,
+    #[serde(default = "default_max_concurrent_requests")]
+    max_concurrent_requests: usize,
+    #[serde(default)]
+    normalize_routes: bool,
     url: Url,
 
     blog_url: Url,
-    #[serde(deserialize_with = "parse_regex")]
-    path_regex: Regex,
+    #[serde(deserialize_with = "parse_regex_or_regexes")]
+    path_regex: Vec<Regex>,
+    #[serde(default, deserialize_with = "parse_rewrites")]
+    page_urls: Vec<(Regex, String)>,
+    public_url: Option<Url>,
     blog_dir: PathBuf,
+    #[serde(default = "default_lock_file")]
+    lock_file: PathBuf,
     blog_build_dir: PathBuf,
     dest_dir: PathBuf,
 
     build_command: Vec<String>,
+    build_sentinel: Option<PathBuf>,
+    build_debounce_ms: Option<u64>,
+    max_pending_builds: Option<usize>,
+    #[serde(default)]
+    allow_skip_build: bool,
+    #[serde(default)]
+    require_note: bool,
+    #[serde(default)]
+    watch_blog_dir: bool,
+    #[serde(default = "default_watch_debounce_ms")]
+    watch_debounce_ms: u64,
+    #[serde(default = "default_busy_timeout_secs")]
+    busy_timeout_secs: u64,
+    #[serde(default)]
+    reject_when_busy: bool,
+    #[serde(default = "default_git_lock_attempts")]
+    git_lock_attempts: u32,
+    #[serde(default = "default_git_lock_backoff_ms")]
+    git_lock_backoff_ms: u64,
     create_revision: Vec<String>,
     stage_revision: Vec<String>,
     reset_command: Vec<String>,
-    list_revisions: Vec<String>,
+    branch_command: Option<Vec<String>>,
+    checkout_command: Option<Vec<String>>,
+    promote_command: Option<Vec<String>>,
     revert_revision: Vec<String>,
+    #[serde(default = "default_show_revision")]
+    show_revision: Vec<String>,
+    #[serde(default = "default_show_file_at_revision")]
+    show_file_at_revision: Vec<String>,
+    revision_files_command: Option<Vec<String>>,
+    #[serde(default = "default_revision_files_limit")]
+    revision_files_limit: usize,
+    #[serde(default = "default_revert_token_ttl_secs")]
+    revert_token_ttl_secs: u64,
     copy_command: Vec<String>,
+    sitemap_command: Option<Vec<String>>,
+    sitemap_path: Option<PathBuf>,
+    #[serde(default)]
+    sitemap_before_build: bool,
+    status_command: Option<Vec<String>>,
+    #[serde(default = "default_diff_command")]
+    diff_command: Vec<String>,
+    validate_command: Option<Vec<String>>,
+    #[serde(default)]
+    format_command: HashMap<String, Vec<String>>,
+    #[serde(default = "default_commit_message_template")]
+    commit_message_template: String,
 
-    templates_dir: PathBuf,
-}
+    templates_dir: Option<PathBuf>,
+    post_templates_dir: Option<PathBuf>,
+    #[serde(default)]
+    mount_prefix: String,
+    #[serde(default)]
+    trust_proxy: bool,
+    #[serde(default)]
+    metrics_allowlist: Vec<String>,
+    #[serde(default = "default_rate_limit_per_minute")]
+    rate_limit_per_minute: u32,
+    #[serde(default = "default_rate_limit_burst")]
+    rate_limit_burst: u32,
 
-fn parse_regex<'de, D>(de: D) -> Result<Regex, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct RegexVisitor {}
-    impl Visitor<'_> for RegexVisitor {
-        type Value = Regex;
-        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            write!(formatter, "URL")
-        }
-        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-        where
-            E: serde::de::Error,
-        {
-            Regex::new(v).map_err(|err| E::custom(format!("{err}")))
-        }
-    }
+    #[serde(default = "default_path_cache_ttl_secs")]
+    path_cache_ttl_secs: u64,
+    #[serde(default = "default_path_cache_size")]
+    path_cache_size: usize,
 
-    de.deserialize_str(RegexVisitor {})
+    #[serde(default = "default_words_per_minute")]
+    words_per_minute: usize,
+    #[serde(default)]
+    count_prose_only: bool,
+
+    #[serde(default = "default_security_headers")]
+    security_headers: HashMap<String, String>,
+    #[serde(default = "default_content_security_policy")]
+    content_security_policy: String,
+    #[serde(default)]
+    debug_errors: bool,
+    #[serde(default = "default_true")]
+    sanitize_render: bool,
+    #[serde(default)]
+    render_allowed_tags: Vec<String>,
+    #[serde(default)]
+    render_allowed_attributes: Vec<String>,
+
+    #[serde(default = "default_true")]
+    compression: bool,
+    #[serde(default = "default_compression_min_bytes")]
+    compression_min_bytes: usize,
+
+    #[serde(default)]
+    cors_allowed_origins: Vec<String>,
+
+    #[serde(default)]
+    users: HashMap<String, String>,
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(default)]
+    roles: HashMap<String, Role>,
+    default_role: Option<Role>,
+
+    schedule_file: Option<PathBuf>,
+    preview_dir: Option<PathBuf>,
+    #[serde(default = "default_preview_ttl_secs")]
+    preview_ttl_secs: u64,
+    drafts_dir: Option<PathBuf>,
+    #[serde(default = "default_autosave_interval_secs")]
+    autosave_interval_secs: u64,
+    #[serde(default = "default_autosave_min_interval_secs")]
+    autosave_min_interval_secs: u64,
+
+    media_dir: Option<PathBuf>,
+    #[serde(default = "default_media_url")]
+    media_url: String,
+    #[serde(default = "default_media_extensions")]
+    media_extensions: Vec<String>,
+    #[serde(default = "default_max_upload_bytes")]
+    max_upload_bytes: u64,
+    #[serde(default = "default_max_content_bytes")]
+    max_content_bytes: usize,
+    #[serde(default)]
+    put_content_types: Vec<String>,
+
+    #[serde(default = "default_link_patterns", deserialize_with = "parse_regexes")]
+    link_patterns: Vec<Regex>,
+    #[serde(default)]
+    block_on_dead_links: bool,
+
+    #[serde(default = "default_blog_fetch_attempts")]
+    blog_fetch_attempts: u32,
+    #[serde(default = "default_blog_fetch_backoff_ms")]
+    blog_fetch_backoff_ms: u64,
+    #[serde(default = "default_blog_fetch_timeout_secs")]
+    blog_fetch_timeout_secs: u64,
+    #[serde(default = "default_max_blog_response_bytes")]
+    max_blog_response_bytes: usize,
+    #[serde(default = "default_true")]
+    follow_redirects: bool,
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+
+    #[serde(default)]
+    normalize_line_endings: bool,
+    #[serde(default)]
+    strip_trailing_whitespace: bool,
+    #[serde(default)]
+    ensure_trailing_newline: bool,
+    #[serde(default, deserialize_with = "parse_encoding")]
+    file_encoding: Option<&'static Encoding>,
+
+    #[serde(default = "default_structured_revisions")]
+    structured_revisions: Vec<String>,
+    #[serde(default = "default_head_revision")]
+    head_revision: Vec<String>,
+    max_revisions: Option<usize>,
+    prune_command: Option<Vec<String>>,
+    prune_interval_secs: Option<u64>,
+    #[serde(default = "default_count_revisions")]
+    count_revisions: Vec<String>,
+
+    webhook_url: Option<Url>,
+    #[serde(default = "default_webhook_actions")]
+    webhook_actions: Vec<String>,
+    #[serde(default = "default_webhook_timeout_secs")]
+    webhook_timeout_secs: u64,
+    #[serde(default = "default_feed_entries")]
+    feed_entries: usize,
+
+    #[serde(default = "default_search_max_results")]
+    search_max_results: usize,
+    #[serde(default = "default_search_snippet_chars")]
+    search_snippet_chars: usize,
+
+    #[serde(default = "default_edit_lock_ttl_secs")]
+    edit_lock_ttl_secs: u64,
+
+    #[serde(default = "default_editable_extensions")]
+    editable_extensions: Vec<String>,
+    #[serde(default)]
+    editable_path_prefixes: Vec<PathBuf>,
+    #[serde(default)]
+    binary_extensions: Vec<String>,
+    #[serde(default)]
+    touch_after_edit: Vec<PathBuf>,
+    #[serde(default = "default_directory_index")]
+    directory_index: String,
+    #[serde(default, deserialize_with = "parse_globs")]
+    protected_paths: GlobSet,
+    #[serde(default)]
+    slugify_filename: bool,
+
+    backup_dir: Option<PathBuf>,
+    #[serde(default = "default_backup_retention")]
+    backup_retention: usize,
+    #[serde(default)]
+    backup_dest_dir: bool,
+    #[serde(default = "default_true")]
+    backup_preserve_mtime: bool,
 }
 
-fn response_with_status<B: Display>(status: StatusCode, body: B) -> Response<String> {
-    Response::builder()
-        .status(status)
-        .body(format!("{}", body))
-        .unwrap()
+fn default_lock_file() -> PathBuf {
+    PathBuf::from(".git/editor.lock")
 }
 
-fn five_hundred<B: Display>(body: B) -> Response<String> {
-    response_with_status(StatusCode::INTERNAL_SERVER_ERROR, body)
+fn default_directory_index() -> String {
+    String::from("index.md")
 }
 
-fn four_hundred<B: Display>(body: B) -> Response<String> {
-    response_with_status(StatusCode::BAD_REQUEST, body)
+fn default_watch_debounce_ms() -> u64 {
+    2000
 }
 
-async fn path_to_file(config: &Config, path: &str) -> Result<PathBuf, Response<String>> {
-    let blog_url = config.blog_url.join(path).unwrap();
+fn default_busy_timeout_secs() -> u64 {
+    30
+}
 
-    let blog_response = match reqwest::get(blog_url).await {
-        Ok(response) => response,
-        Err(err) => {
-            return Err(five_hundred(err));
-        }
-    };
+fn default_header_read_timeout_secs() -> u64 {
+    30
+}
 
-    if !blog_response.status().is_success() {
-        return Err(Response::builder()
-            .header("Content-Type", "text/html")
-            .body(format!(
-                "<head><meta http-equiv=\"Refresh\" content=\"0; URL={}publish{}\"></head>",
-                config.url, path
-            ))
-            .unwrap());
-    }
+fn default_max_concurrent_requests() -> usize {
+    256
+}
 
-    let blog_text = blog_response.text().await.map_err(five_hundred)?;
+fn default_request_timeout_secs() -> u64 {
+    120
+}
 
-    let relative_path = match config.path_regex.captures(&blog_text) {
-        Some(captures) => captures,
-        None => {
-            return Err(five_hundred(format!(
-                "nothing matching {} in {}",
-                config.path_regex, blog_text
-            )))
-        }
-    };
+fn default_commit_message_template() -> String {
+    String::from(
+        "{% if user %}{{ user }}: {% endif %}{% if note %}{{ note }} - {% endif %}{{ action }} {{ path }}",
+    )
+}
 
-    let mut page_path = config.blog_dir.clone();
-    page_path.push(&relative_path[1]);
-    let actual_path = page_path.canonicalize().map_err(five_hundred)?;
+fn default_preview_ttl_secs() -> u64 {
+    3600
+}
 
-    if !actual_path.starts_with(&config.blog_dir) {
-        println!(
-            "cheating bastard: {} does NOT start with {}",
-            actual_path.display(),
-            config.blog_dir.display()
-        );
-        return Err(four_hundred("cheating bastard"));
-    }
+fn default_autosave_interval_secs() -> u64 {
+    30
+}
 
-    Ok(actual_path)
+fn default_autosave_min_interval_secs() -> u64 {
+    5
 }
 
-async fn command_stdout(
-    config: &Config,
-    args: impl Iterator<Item = &str>,
-) -> Result<String, Response<String>> {
-    let args = args.collect::<Vec<&str>>();
-    let mut command = Command::new(args[0]);
-    for arg in &args[1..] {
-        command.arg(arg);
-    }
+fn default_media_url() -> String {
+    String::from("/media/")
+}
 
-    command.current_dir(&config.blog_dir);
-    let output = command.output().await.map_err(five_hundred)?;
+fn default_media_extensions() -> Vec<String> {
+    ["png", "jpg", "jpeg", "gif", "webp", "svg"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    if !output.status.success() {
-        let all_output = String::from("failed: ")
-            + &args.join(" ")
-            + "\nstdout:\n"
-            + &String::from_utf8_lossy(&output.stdout)
-            + "\nstderr:\n"
-            + &String::from_utf8_lossy(&output.stderr);
-        return Err(five_hundred(all_output));
-    }
+fn default_editable_extensions() -> Vec<String> {
+    ["md", "markdown", "html", "htm", "txt"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    Ok(String::from_utf8_lossy(&output.stdout).into())
+fn default_max_upload_bytes() -> u64 {
+    10 * 1024 * 1024
 }
 
-async fn rebuild(config: &Config) -> Result<String, Response<String>> {
-    let blog_build_output =
-        command_stdout(config, config.build_command.iter().map(|s| s.as_str())).await?;
+fn default_max_content_bytes() -> usize {
+    5 * 1024 * 1024
+}
 
-    if tokio::fs::try_exists(&config.dest_dir)
-        .await
-        .map_err(five_hundred)?
-    {
-        tokio::fs::remove_dir_all(&config.dest_dir)
-            .await
-            .map_err(five_hundred)?;
-    }
+fn default_link_patterns() -> Vec<Regex> {
+    vec![
+        Regex::new(r"\]\(([^)\s]+)").unwrap(),
+        Regex::new(r#"(?:href|src)\s*=\s*["']([^"']+)["']"#).unwrap(),
+    ]
+}
 
-    Ok(blog_build_output)
+fn default_git_lock_attempts() -> u32 {
+    5
 }
 
-async fn reset_if_err(
-    config: &Config,
-    f: impl Future<Output = Result<String, Response<String>>>,
-) -> Result<String, Response<String>> {
-    match f.await {
-        Ok(ok) => Ok(ok),
-        Err(mut err) => {
-            match command_stdout(config, config.reset_command.iter().map(|s| s.as_str())).await {
-                Ok(ok) => err
-                    .body_mut()
-                    .push_str(&format!("\n\nhad to reset\n\n{}", ok)),
-                Err(err2) => err
-                    .body_mut()
-                    .push_str(&format!("\n\nfailed resetting\n\n{}", err2.body())),
-            }
-            Err(err)
-        }
-    }
+fn default_git_lock_backoff_ms() -> u64 {
+    100
 }
 
-async fn set_content_with_revision(
-    config: &Config,
-    actual_path: &Path,
-    content: &str,
-    note: Option<&str>,
-) -> Result<String, Response<String>> {
-    match tokio::fs::write(&actual_path, &content).await {
-        Ok(_) => {}
-        Err(_) => return Err(five_hundred("couldn't write")),
-    }
+fn default_blog_fetch_attempts() -> u32 {
+    3
+}
 
-    let message = format!(
-        "{}edit {}",
-        if let Some(note) = note {
-            format!("{} - ", note)
-        } else {
-            String::new()
-        },
-        actual_path
-            .strip_prefix(&config.blog_dir)
-            .unwrap()
-            .display()
-    );
+fn default_blog_fetch_backoff_ms() -> u64 {
+    200
+}
 
-    create_revision(config, actual_path, message).await
+fn default_blog_fetch_timeout_secs() -> u64 {
+    30
 }
 
-async fn create_revision(
-    config: &Config,
-    actual_path: &Path,
-    message: String,
-) -> Result<String, Response<String>> {
-    let path = format!("{}", actual_path.display());
+fn default_max_blog_response_bytes() -> usize {
+    10 * 1024 * 1024
+}
 
-    let mut revision_output = rebuild(config).await?;
+fn default_max_redirects() -> usize {
+    10
+}
 
-    revision_output.push_str(
-        &command_stdout(
-            config,
-            config
-                .stage_revision
-                .iter()
-                .map(|s| s.as_str())
-                .chain([path.as_str()]),
-        )
-        .await?,
-    );
+fn default_user_agent() -> String {
+    format!("editor/{}", env!("CARGO_PKG_VERSION"))
+}
 
-    revision_output.push_str(
-        &command_stdout(
-            config,
-            config
-                .create_revision
-                .iter()
-                .map(|s| s.as_str())
-                .chain([message.as_str()]),
-        )
-        .await?,
-    );
+fn default_show_revision() -> Vec<String> {
+    ["git", "show", "--stat", "--patch"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    revision_output.push_str(
-        &command_stdout(
-            config,
-            config.copy_command.iter().map(|s| s.as_str()).chain([
-                format!("{}", config.blog_build_dir.display()).as_str(),
-                format!("{}", config.dest_dir.display()).as_str(),
-            ]),
-        )
-        .await?,
-    );
+fn default_show_file_at_revision() -> Vec<String> {
+    vec![String::from("git"), String::from("show")]
+}
 
-    Ok(revision_output)
+fn default_revision_files_limit() -> usize {
+    30
 }
 
-fn normalize_path(path: &Path) -> PathBuf {
-    let mut components = path.components().peekable();
-    let mut ret = if let Some(c @ Component::Prefix(..)) = components.peek().cloned() {
-        components.next();
-        PathBuf::from(c.as_os_str())
-    } else {
-        PathBuf::new()
-    };
+fn default_revert_token_ttl_secs() -> u64 {
+    600
+}
 
-    for component in components {
-        match component {
-            Component::Prefix(..) => unreachable!(),
-            Component::RootDir => {
-                ret.push(component.as_os_str());
-            }
-            Component::CurDir => {}
-            Component::ParentDir => {
-                ret.pop();
-            }
-            Component::Normal(c) => {
-                ret.push(c);
-            }
-        }
-    }
-    ret
+fn default_structured_revisions() -> Vec<String> {
+    ["git", "log", "--pretty=format:%h%x09%aI%x09%an%x09%s"]
+        .into_iter()
+        .map(String::from)
+        .collect()
 }
 
-async fn get_revert(config: &Config, tera: &Tera) -> Result<Response<String>, Response<String>> {
-    let list_revert_output =
-        command_stdout(config, config.list_revisions.iter().map(|s| s.as_str())).await?;
-    let revisions = list_revert_output.split("\n").collect::<Vec<_>>();
+fn default_head_revision() -> Vec<String> {
+    ["git", "rev-parse", "--short", "HEAD"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    let mut context = Context::new();
-    context.insert("revisions", &revisions);
-    let page = tera.render("revert.html", &context).map_err(five_hundred)?;
+fn default_diff_command() -> Vec<String> {
+    ["git", "diff", "HEAD"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    Ok(Response::builder().body(page).unwrap())
+fn default_count_revisions() -> Vec<String> {
+    ["git", "rev-list", "--count", "HEAD"]
+        .into_iter()
+        .map(String::from)
+        .collect()
 }
 
-async fn get_edit(
-    config: &Config,
-    tera: &Tera,
-    path: FullPath,
-) -> Result<Response<String>, Response<String>> {
-    let path_str = path.as_str().strip_prefix("/edit").unwrap();
-    let actual_path = path_to_file(config, path_str).await?;
+fn default_webhook_actions() -> Vec<String> {
+    ["edit", "publish", "delete", "upload"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
 
-    let page_content = match tokio::fs::read_to_string(&actual_path).await {
-        Ok(content) => content,
-        Err(_) => {
-            return Err(five_hundred(format!(
-                "couldn't read {}",
-                actual_path.display()
-            )))
-        }
-    };
+fn default_webhook_timeout_secs() -> u64 {
+    5
+}
 
-    let mut context = Context::new();
-    context.insert("content", &page_content);
+fn default_feed_entries() -> usize {
+    20
+}
 
-    let page = match tera.render("edit.html", &context) {
-        Ok(page) => page,
-        Err(err) => return Err(five_hundred(err)),
-    };
+fn default_search_max_results() -> usize {
+    50
+}
 
-    let response = Response::builder()
-        .header("Content-Type", "text/html")
-        .body(page)
-        .unwrap();
+fn default_search_snippet_chars() -> usize {
+    120
+}
 
-    Ok(response)
+fn default_edit_lock_ttl_secs() -> u64 {
+    300
 }
 
-async fn get_publish(_config: &Config, tera: &Tera) -> Result<Response<String>, Response<String>> {
-    let page = match tera.render("publish.html", &Context::new()) {
-        Ok(page) => page,
-        Err(err) => return Ok(five_hundred(err)),
-    };
+fn default_backup_retention() -> usize {
+    10
+}
 
-    let response = Response::builder()
-        .header("Content-Type", "text/html")
-        .body(page)
-        .unwrap();
+fn default_rate_limit_per_minute() -> u32 {
+    12
+}
 
-    Ok(response)
+fn default_rate_limit_burst() -> u32 {
+    10
 }
 
-async fn post_revert(
-    config: &Config,
-    form: HashMap<String, String>,
-) -> Result<Response<String>, Response<String>> {
-    let Some(revision_name) = form.get("revision") else {
-        return Err(four_hundred("no revision from form?"));
-    };
+fn default_true() -> bool {
+    true
+}
 
-    let Some(revision) = revision_name.split_whitespace().next() else {
-        return Err(four_hundred(format!(
-            "no hash in revision {}",
-            revision_name
-        )));
-    };
+fn default_compression_min_bytes() -> usize {
+    1024
+}
 
-    let do_revert_output = command_stdout(
-        config,
-        config
-            .revert_revision
-            .iter()
-            .map(|s| s.as_str())
-            .chain([revision]),
-    )
-    .await?;
+fn default_path_cache_ttl_secs() -> u64 {
+    60
+}
 
-    Ok(Response::builder().body(do_revert_output).unwrap())
+fn default_path_cache_size() -> usize {
+    1024
 }
 
-async fn post_edit(
-    config: &Config,
-    path: FullPath,
-    form: HashMap<String, String>,
-) -> Result<Response<String>, Response<String>> {
-    let path_str = path.as_str().strip_prefix("/edit").unwrap();
-    let actual_path = path_to_file(config, path_str).await?;
+fn default_words_per_minute() -> usize {
+    200
+}
 
-    let Some(content) = form.get("content") else {
-        return Err(four_hundred("no content from form?"));
-    };
+fn default_security_headers() -> HashMap<String, String> {
+    HashMap::from([
+        ("X-Content-Type-Options".into(), "nosniff".into()),
+        ("X-Frame-Options".into(), "DENY".into()),
+        ("Referrer-Policy".into(), "same-origin".into()),
+    ])
+}
 
-    if form.get("delete").map(|s| s.as_str()) == Some("on") {
-        match tokio::fs::remove_file(&actual_path).await {
-            Ok(_) => {}
-            Err(err) => return Err(five_hundred(err)),
+fn default_content_security_policy() -> String {
+    "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; img-src 'self' data:".into()
+}
+
+#[derive(Default)]
+struct State {
+    client: reqwest::Client,
+    secret: Vec<u8>,
+    used_revert_tokens: Mutex<HashMap<String, i64>>,
+    revision_files: Mutex<HashMap<String, Vec<String>>>,
+    path_cache: Mutex<HashMap<String, CachedPath>>,
+    schedule: tokio::sync::Mutex<Vec<ScheduledPost>>,
+    publish_page: Mutex<Option<String>>,
+    edit_locks: Mutex<HashMap<PathBuf, EditLock>>,
+    autosaves: Mutex<HashMap<String, Instant>>,
+    rate_limits: Mutex<HashMap<String, (f64, Instant)>>,
+    /// Held while the blog directory is being changed or built, so edits and builds don't
+    /// interleave.
+    writes: tokio::sync::Mutex<()>,
+    /// How many writes are waiting on writes.
+    writes_queued: AtomicUsize,
+    /// Set when reset_command fails or leaves a dirty tree, or when the tree is left on another
+    /// branch. Writes are refused and /health fails until an admin clears it.
+    degraded: RwLock<Option<String>>,
+    builds: Mutex<BuildQueue>,
+    build_status: RwLock<BuildStatus>,
+}
+
+struct CachedPath {
+    cached_at: Instant,
+    used_at: Instant,
+    actual_path: PathBuf,
+}
+
+struct EditLock {
+    session: String,
+    expires_at: Instant,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+const DURATION_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+struct Metrics {
+    registry: Registry,
+    edits: IntCounter,
+    deletes: IntCounter,
+    publishes: IntCounter,
+    reverts: IntCounter,
+    rebuilds: IntCounter,
+    escapes: IntCounter,
+    build_duration: Histogram,
+    render_duration: HistogramVec,
+    blog_request_duration: Histogram,
+    pending_builds: IntGauge,
+    requests: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let histogram = |name: &str, help: &str| {
+            let histogram = Histogram::with_opts(
+                HistogramOpts::new(name, help).buckets(DURATION_BUCKETS.into()),
+            )
+            .unwrap();
+            registry.register(Box::new(histogram.clone())).unwrap();
+            histogram
         };
 
-        let create_revision_output = reset_if_err(
-            config,
-            create_revision(
-                config,
-                &actual_path,
-                format!("delete {}", actual_path.display()),
-            ),
+        let pending_builds = IntGauge::new(
+            "editor_pending_builds",
+            "Revisions waiting on a debounced build",
         )
-        .await?;
+        .unwrap();
+        registry.register(Box::new(pending_builds.clone())).unwrap();
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "editor_render_duration_seconds",
+                "Time spent rendering templates",
+            )
+            .buckets(DURATION_BUCKETS.into()),
+            &["template"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(render_duration.clone()))
+            .unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("editor_http_requests_total", "Requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
 
-        Ok(Response::builder()
-            .body(format!(
-                "deleted {}\n\n{}",
-                actual_path.display(),
-                create_revision_output
-            ))
-            .unwrap())
-    } else {
-        let set_content_and_create_revision_output = reset_if_err(
-            config,
-            set_content_with_revision(
-                config,
-                actual_path.as_path(),
-                content.as_str(),
-                form.get("note").map(|s| s.as_str()),
+        Metrics {
+            edits: counter("editor_edits_total", "Edits written"),
+            deletes: counter("editor_deletes_total", "Files deleted"),
+            publishes: counter("editor_publishes_total", "Posts published"),
+            reverts: counter("editor_reverts_total", "Revisions reverted"),
+            rebuilds: counter("editor_rebuilds_total", "Blog rebuilds"),
+            escapes: counter(
+                "editor_path_escapes_total",
+                "Requests for paths outside an allowed directory",
             ),
-        )
-        .await?;
-        Ok(Response::builder()
-            .body(format!(
-                "wrote to {}\n\n{}",
-                actual_path.display(),
-                set_content_and_create_revision_output
-            ))
-            .unwrap())
+            build_duration: histogram(
+                "editor_build_duration_seconds",
+                "Time spent running build_command",
+            ),
+            render_duration,
+            blog_request_duration: histogram(
+                "editor_blog_request_duration_seconds",
+                "Latency of requests to blog_url",
+            ),
+            pending_builds,
+            requests,
+            registry,
+        }
+    }
+
+    fn request(&self, method: String, route: &'static str, status: StatusCode) {
+        self.requests
+            .with_label_values(&[method.as_str(), route, status.as_str()])
+            .inc();
+    }
+
+    fn render(&self, state: &State) -> String {
+        self.pending_builds
+            .set(state.builds.lock().unwrap().pending as i64);
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap()
     }
 }
 
-async fn post_publish(
-    config: &Config,
-    form: HashMap<String, String>,
-) -> Result<Response<String>, Response<String>> {
-    let Some(filename) = form.get("filename") else {
-        return Err(four_hundred("missing filename"));
-    };
+fn metrics_route(config: &Config, path: &str) -> &'static str {
+    let path = path
+        .strip_prefix(config.mount_prefix.as_str())
+        .unwrap_or(path);
+    let segment = path.trim_start_matches('/').split('/').next();
+    [
+        "edit",
+        "publish",
+        "revert",
+        "feed.xml",
+        "search",
+        "upload",
+        "media",
+        "built",
+        "preview-site",
+        "admin",
+        "api",
+        "metrics",
+        "health",
+    ]
+    .into_iter()
+    .find(|route| Some(*route) == segment)
+    .unwrap_or("other")
+}
 
-    let Some(content) = form.get("content") else {
-        return Err(four_hundred("missing content"));
+/// Methods the routes in main accept for a path, empty if none of them match it.
+fn allowed_methods(config: &Config, path: &str) -> &'static [&'static str] {
+    let Some(path) = path.strip_prefix(config.mount_prefix.as_str()) else {
+        return &[];
     };
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("revert" | "publish"), _) => &["GET", "POST"],
+        (Some("edit"), _) => &["GET", "POST", "PUT"],
+        (Some("feed.xml" | "search" | "built" | "metrics" | "health"), _) => &["GET"],
+        (Some("media"), _) if config.media_dir.is_some() => &["GET"],
+        (Some("preview-site"), _) if config.preview_dir.is_some() => &["GET"],
+        (Some("upload" | "restore"), _) => &["POST"],
+        (Some("admin"), Some("status" | "diff" | "scheduled" | "build-status")) => &["GET"],
+        (
+            Some("admin"),
+            Some("discard" | "rebuild" | "promote" | "clear-degraded" | "reload-templates"),
+        ) => &["POST"],
+        (Some("api"), Some("content" | "content-at" | "revisions" | "whoami")) => &["GET"],
+        (Some("api"), Some("batch")) => &["POST"],
+        _ => &[],
+    }
+}
 
-    let actual_path = normalize_path(config.blog_dir.join(filename).as_path());
-    if !actual_path.starts_with(&config.blog_dir) {
-        println!(
-            "cheating bastard: {} does NOT start with {}",
-            actual_path.display(),
-            config.blog_dir.display()
-        );
-        return Err(four_hundred("cheating bastard"));
+/// 405 with an Allow header if some route matches the path but not the method, otherwise 404.
+fn no_route(config: &Config, method: &Method, path: &FullPath) -> Response<String> {
+    let allowed = allowed_methods(config, path.as_str());
+    if allowed.is_empty() || allowed.contains(&method.as_str()) {
+        return response_with_status(StatusCode::NOT_FOUND, format!("404: {}", path.as_str()));
     }
 
-    if tokio::fs::try_exists(&actual_path)
-        .await
-        .map_err(five_hundred)?
-    {
-        return Err(four_hundred("already exists"));
+    let mut response = response_with_status(
+        StatusCode::METHOD_NOT_ALLOWED,
+        format!("405: {} {}", method, path.as_str()),
+    );
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_str(&allowed.join(", ")).unwrap());
+    response
+}
+
+/// no_route, with not_found.html instead of a plain 404. The plain one is kept if rendering fails.
+fn not_found_page(
+    config: &Config,
+    tera: &RwLock<Tera>,
+    method: &Method,
+    path: &FullPath,
+) -> Response<String> {
+    let response = no_route(config, method, path);
+    if response.status() != StatusCode::NOT_FOUND {
+        return response;
     }
 
-    let stdout = reset_if_err(
-        config,
-        set_content_with_revision(
-            config,
-            actual_path.as_path(),
-            content.as_str(),
-            form.get("note").map(|s| s.as_str()),
-        ),
-    )
-    .await?;
+    let mut context = base_context(config);
+    context.insert("path", path.as_str());
+    match render_template(tera, "not_found.html", &context) {
+        Ok(page) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/html")
+            .body(page)
+            .unwrap(),
+        Err(err) => {
+            render_error(config, "not_found.html", err);
+            response
+        }
+    }
+}
 
-    Ok(Response::builder()
-        .body(format!("wrote to {}\n\n{}", actual_path.display(), stdout))
-        .unwrap())
+/// Lowercases the route segment after the mount prefix and drops a trailing slash after it, so
+/// /Edit/page and /status/ match like /edit/page and /status. None if nothing changed
+fn normalize_route(config: &Config, path: &str) -> Option<String> {
+    let rest = path.strip_prefix(config.mount_prefix.as_str())?;
+    let rest = rest.strip_prefix('/')?;
+    let (segment, after) = rest.split_once('/').unwrap_or((rest, ""));
+    let normalized = if after.is_empty() {
+        format!("{}/{}", config.mount_prefix, segment.to_lowercase())
+    } else {
+        format!(
+            "{}/{}/{}",
+            config.mount_prefix,
+            segment.to_lowercase(),
+            after
+        )
+    };
+    (normalized != path && !segment.is_empty()).then_some(normalized)
 }
 
-#[tokio::main]
-async fn main() {
-    let config_buf = std::fs::read_to_string(std::env::args().nth(1).unwrap()).unwrap();
-    let mut config: Config = toml::from_str(&config_buf).unwrap();
-    config.blog_dir = config.blog_dir.canonicalize().unwrap();
-    config.blog_build_dir = config.blog_build_dir.canonicalize().unwrap();
-    config.dest_dir = config.dest_dir.canonicalize().unwrap();
-    config.templates_dir = config.templates_dir.canonicalize().unwrap();
-    let config: &'static Config = Box::leak(Box::new(config));
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct ScheduledPost {
+    path: PathBuf,
+    content: String,
+    note: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    publish_at: DateTime<Utc>,
+}
 
-    let templates_pattern = config.templates_dir.join("**").join("*.html");
-    let tera: &'static _ = Box::leak(Box::new(
-        Tera::new(&format!("{}", templates_pattern.display())).unwrap(),
-    ));
+impl State {
+    fn new(config: &Config) -> State {
+        let redirect = if config.follow_redirects {
+            reqwest::redirect::Policy::limited(config.max_redirects)
+        } else {
+            reqwest::redirect::Policy::none()
+        };
 
-    let get_revert = warp::get()
-        .and(warp::path("revert"))
-        .and_then(move || async move {
-            // moar keywords
-            match get_revert(config, tera).await {
-                Ok(ok) => Ok::<_, Rejection>(ok),
-                Err(err) => Ok(err),
+        let mut secret = vec![0u8; 32];
+        getrandom::getrandom(&mut secret).expect("the OS random number generator failed");
+
+        State {
+            secret,
+            client: reqwest::Client::builder()
+                .redirect(redirect)
+                .user_agent(&config.user_agent)
+                .build()
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// Entries for files that have since been deleted or moved away are dropped rather than
+    /// returned, so the caller goes back to the blog for the new location.
+    fn cached_path(&self, config: &Config, path: &str) -> Option<PathBuf> {
+        let mut path_cache = self.path_cache.lock().unwrap();
+        match path_cache.get_mut(path) {
+            Some(cached)
+                if cached.cached_at.elapsed() < Duration::from_secs(config.path_cache_ttl_secs)
+                    && cached.actual_path.is_file() =>
+            {
+                cached.used_at = Instant::now();
+                Some(cached.actual_path.clone())
             }
-        });
-    let post_revert = warp::post()
-        .and(warp::path("revert"))
-        .and(warp::filters::body::form())
-        .and_then(move |form: HashMap<String, String>| async move {
-            match post_revert(config, form).await {
-                Ok(ok) => Ok::<_, Rejection>(ok),
-                Err(err) => Ok(err),
+            Some(_) => {
+                path_cache.remove(path);
+                None
             }
-        });
+            None => None,
+        }
+    }
 
-    let get_edit = warp::get()
-        .and(warp::path("edit"))
-        .and(warp::path::full())
-        .and_then(move |path| async move {
-            match get_edit(config, tera, path).await {
-                Ok(ok) => Ok::<_, Rejection>(ok),
-                Err(err) => Ok(err),
+    fn cache_path(&self, config: &Config, path: &str, actual_path: &Path) {
+        if config.path_cache_size == 0 {
+            return;
+        }
+
+        let mut path_cache = self.path_cache.lock().unwrap();
+        if path_cache.len() >= config.path_cache_size && !path_cache.contains_key(path) {
+            let least_recent = path_cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.used_at)
+                .map(|(path, _)| path.clone());
+            if let Some(least_recent) = least_recent {
+                path_cache.remove(&least_recent);
             }
-        });
-    let post_edit = warp::post()
-        .and(warp::path("edit"))
-        .and(warp::path::full())
-        .and(warp::filters::body::form())
-        .and_then(
-            move |path: FullPath, form: HashMap<String, String>| async move {
-                match post_edit(config, path, form).await {
-                    Ok(ok) => Ok::<_, Rejection>(ok),
-                    Err(err) => Ok(err),
-                }
+        }
+        let now = Instant::now();
+        path_cache.insert(
+            path.into(),
+            CachedPath {
+                cached_at: now,
+                used_at: now,
+                actual_path: actual_path.into(),
             },
         );
+    }
 
-    let get_publish = warp::get()
-        .and(warp::path("publish"))
-        .and_then(move || async move {
-            match get_publish(config, tera).await {
-                Ok(ok) => Ok::<_, Rejection>(ok),
-                Err(err) => Ok(err),
+    /// Drops every request path that resolved to the file, not just the one it was edited by.
+    fn invalidate_path(&self, actual_path: &Path) {
+        self.path_cache
+            .lock()
+            .unwrap()
+            .retain(|_, cached| cached.actual_path != actual_path);
+    }
+
+    fn new_session(&self) -> String {
+        random_token()
+    }
+
+    fn lock_path(&self, config: &Config, actual_path: &Path, session: &str) -> bool {
+        let mut edit_locks = self.edit_locks.lock().unwrap();
+        let now = Instant::now();
+        edit_locks.retain(|_, lock| lock.expires_at > now);
+
+        match edit_locks.get(actual_path) {
+            Some(lock) if lock.session != session => false,
+            _ => {
+                edit_locks.insert(
+                    actual_path.into(),
+                    EditLock {
+                        session: session.into(),
+                        expires_at: now + Duration::from_secs(config.edit_lock_ttl_secs),
+                    },
+                );
+                true
             }
-        });
-    let post_publish = warp::post()
-        .and(warp::path("publish"))
-        .and(warp::filters::body::form())
-        .and_then(move |form: HashMap<String, String>| async move {
-            match post_publish(config, form).await {
-                Ok(ok) => Ok::<_, Rejection>(ok),
-                Err(err) => Ok(err),
+        }
+    }
+
+    // returns how long the client has to wait for a token
+    fn take_token(&self, config: &Config, client: &str) -> Option<Duration> {
+        if config.rate_limit_per_minute == 0 {
+            return None;
+        }
+
+        let rate = config.rate_limit_per_minute as f64 / 60.0;
+        let burst = config.rate_limit_burst.max(1) as f64;
+        let refill = |(tokens, filled_at): &(f64, Instant)| {
+            (tokens + filled_at.elapsed().as_secs_f64() * rate).min(burst)
+        };
+
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        rate_limits.retain(|_, bucket| refill(bucket) < burst);
+
+        let tokens = rate_limits.get(client).map(refill).unwrap_or(burst);
+        if tokens >= 1.0 {
+            rate_limits.insert(client.into(), (tokens - 1.0, Instant::now()));
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - tokens) / rate))
+        }
+    }
+
+    // returns how long the session has to wait before autosaving again
+    fn autosave_wait(&self, config: &Config, session: &str) -> Option<Duration> {
+        let mut autosaves = self.autosaves.lock().unwrap();
+        let min_interval = Duration::from_secs(config.autosave_min_interval_secs);
+        autosaves.retain(|_, saved_at| saved_at.elapsed() < min_interval);
+
+        match autosaves.get(session) {
+            Some(saved_at) => Some(min_interval.saturating_sub(saved_at.elapsed())),
+            None => {
+                autosaves.insert(session.into(), Instant::now());
+                None
             }
-        });
+        }
+    }
 
-    let route = get_revert
-        .or(post_revert)
-        .or(get_edit)
-        .or(post_edit)
-        .or(get_publish)
-        .or(post_publish)
-        .or(warp::any().and(warp::path::full()).map(|path: FullPath| {
-            response_with_status(StatusCode::NOT_FOUND, format!("404: {}", path.as_str()))
-        }));
+    fn revert_signature(&self, revision: &str, expires: i64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(format!("{}:{}", revision, expires).as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn revert_token(&self, config: &Config, revision: &str) -> String {
+        let expires = Utc::now().timestamp() + config.revert_token_ttl_secs as i64;
+        format!("{}.{}", expires, self.revert_signature(revision, expires))
+    }
+
+    /// Returns when the token expires, it's only used up by use_revert_token once the revert
+    /// goes through.
+    fn check_revert_token(&self, revision: &str, token: &str) -> Result<i64, String> {
+        let Some((expires, signature)) = token.split_once('.') else {
+            return Err(String::from("malformed revert token"));
+        };
+        let expires = expires
+            .parse::<i64>()
+            .map_err(|_| String::from("malformed revert token"))?;
+
+        let expected = self.revert_signature(revision, expires);
+        if !bool::from(expected.as_bytes().ct_eq(signature.as_bytes())) {
+            return Err(format!("revert token isn't for {}", revision));
+        }
+
+        let now = Utc::now().timestamp();
+        if expires < now {
+            return Err(String::from(
+                "revert token expired, preview the revision again",
+            ));
+        }
+
+        let mut used_revert_tokens = self.used_revert_tokens.lock().unwrap();
+        used_revert_tokens.retain(|_, expires| *expires >= now);
+        if used_revert_tokens.contains_key(token) {
+            return Err(String::from("revert token was already used"));
+        }
+
+        Ok(expires)
+    }
+
+    fn use_revert_token(&self, token: &str, expires: i64) {
+        let mut used_revert_tokens = self.used_revert_tokens.lock().unwrap();
+        used_revert_tokens.insert(token.into(), expires);
+    }
+
+    fn is_locked(&self, actual_path: &Path) -> bool {
+        let edit_locks = self.edit_locks.lock().unwrap();
+        edit_locks
+            .get(actual_path)
+            .is_some_and(|lock| lock.expires_at > Instant::now())
+    }
+
+    fn unlock_path(&self, actual_path: &Path, session: Option<&str>) -> bool {
+        let mut edit_locks = self.edit_locks.lock().unwrap();
+        match (edit_locks.get(actual_path), session) {
+            (Some(lock), Some(session)) if lock.session != session => false,
+            (Some(_), _) => edit_locks.remove(actual_path).is_some(),
+            (None, _) => false,
+        }
+    }
+}
+
+fn parse_regex_or_regexes<'de, D>(de: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct RegexVisitor {}
+    impl<'de> Visitor<'de> for RegexVisitor {
+        type Value = Vec<Regex>;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "a regex or a list of regexes")
+        }
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Regex::new(v)
+                .map(|regex| vec![regex])
+                .map_err(|err| E::custom(format!("{err}")))
+        }
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            let mut regexes = Vec::new();
+            while let Some(regex) = seq.next_element::<String>()? {
+                regexes.push(Regex::new(&regex).map_err(serde::de::Error::custom)?);
+            }
+            Ok(regexes)
+        }
+    }
 
-    let server = warp::serve(route);
-    server.bind(config.bind).await;
+    de.deserialize_any(RegexVisitor {})
+}
+
+fn parse_globs<'de, D>(de: D) -> Result<GlobSet, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut globs = GlobSetBuilder::new();
+    for glob in Vec::<String>::deserialize(de)? {
+        globs.add(Glob::new(&glob).map_err(serde::de::Error::custom)?);
+    }
+    globs.build().map_err(serde::de::Error::custom)
+}
+
+fn parse_encoding<'de, D>(de: D) -> Result<Option<&'static Encoding>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let label = String::deserialize(de)?;
+    let encoding = Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown encoding {}", label)))?;
+    // encoding_rs only decodes UTF-16, encoding to it produces UTF-8
+    if encoding.output_encoding() != encoding {
+        return Err(serde::de::Error::custom(format!(
+            "can't write files as {}",
+            encoding.name()
+        )));
+    }
+    Ok(Some(encoding))
+}
+
+fn parse_regexes<'de, D>(de: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(de)?
+        .iter()
+        .map(|regex| Regex::new(regex).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn parse_rewrites<'de, D>(de: D) -> Result<Vec<(Regex, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<(String, String)>::deserialize(de)?
+        .into_iter()
+        .map(|(regex, replacement)| {
+            Regex::new(&regex)
+                .map(|regex| (regex, replacement))
+                .map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+/// Where a file in blog_dir ends up on the blog, from the first of page_urls that matches its
+/// path relative to blog_dir.
+fn page_url(config: &Config, actual_path: &Path) -> Option<Url> {
+    let relative_path = actual_path
+        .strip_prefix(&config.blog_dir)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let (regex, replacement) = config
+        .page_urls
+        .iter()
+        .find(|(regex, _)| regex.is_match(&relative_path))?;
+    config
+        .public_url
+        .as_ref()
+        .unwrap_or(&config.blog_url)
+        .join(&regex.replace(&relative_path, replacement.as_str()))
+        .ok()
+}
+
+fn mounted_url(config: &Config, url: &Url) -> String {
+    format!(
+        "{}{}/",
+        url.as_str().trim_end_matches('/'),
+        config.mount_prefix
+    )
+}
+
+/// Where the publish routes in main are mounted, under mount_prefix.
+const PUBLISH_ROUTE: &str = "publish";
+
+/// route (and anything after it) under mount_prefix on url, however url's slashes are.
+fn route_url(config: &Config, url: &Url, route: &str) -> Url {
+    // mounted_url always ends in a slash, so route is joined onto it instead of replacing the
+    // last segment, and a leading slash would go to the root instead
+    Url::parse(&mounted_url(config, url))
+        .and_then(|mounted| mounted.join(route.trim_start_matches('/')))
+        .unwrap_or_else(|_| url.clone())
+}
+
+struct Forwarded {
+    client: String,
+    url: Url,
+}
+
+impl Forwarded {
+    fn new(config: &Config, remote: Option<SocketAddr>, headers: &HeaderMap) -> Forwarded {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+
+        let remote = remote
+            .map(|remote| remote.ip().to_string())
+            .unwrap_or_else(|| String::from("unknown"));
+        if !config.trust_proxy {
+            return Forwarded {
+                client: remote,
+                url: config.url.clone(),
+            };
+        }
+
+        let proto = header("x-forwarded-proto").unwrap_or(config.url.scheme());
+        let url = match header("x-forwarded-host") {
+            Some(host) => Url::parse(&format!("{}://{}{}", proto, host, config.url.path()))
+                .unwrap_or_else(|_| config.url.clone()),
+            None => {
+                let mut url = config.url.clone();
+                let _ = url.set_scheme(proto);
+                url
+            }
+        };
+
+        Forwarded {
+            client: header("x-forwarded-for")
+                .map(String::from)
+                .unwrap_or(remote),
+            url,
+        }
+    }
+}
+
+fn base_context(config: &Config) -> Context {
+    let mut context = Context::new();
+    context.insert("mount_prefix", &config.mount_prefix);
+    context.insert("allow_skip_build", &config.allow_skip_build);
+    context.insert("require_note", &config.require_note);
+    context.insert("allow_branch", &allow_branch(config));
+    context.insert("allow_promote", &config.promote_command.is_some());
+    context
+}
+
+fn response_with_status<B: Display>(status: StatusCode, body: B) -> Response<String> {
+    Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .status(status)
+        .body(format!("{}", body))
+        .unwrap()
+}
+
+fn five_hundred<B: Display>(body: B) -> Response<String> {
+    response_with_status(StatusCode::INTERNAL_SERVER_ERROR, body)
+}
+
+/// Renders a template, timing it for editor_render_duration_seconds.
+fn render_template(tera: &RwLock<Tera>, name: &str, context: &Context) -> tera::Result<String> {
+    let started = Instant::now();
+    let rendered = tera.read().unwrap().render(name, context);
+    METRICS
+        .render_duration
+        .with_label_values(&[name])
+        .observe(started.elapsed().as_secs_f64());
+    rendered
+}
+
+/// Template errors can quote the template and whatever was being rendered into it, so only the
+/// log gets the details unless debug_errors is set.
+fn render_error(config: &Config, what: &str, err: tera::Error) -> Response<String> {
+    let mut detail = err.to_string();
+    let mut source = std::error::Error::source(&err);
+    while let Some(err) = source {
+        detail.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    println!("WARNING: couldn't render {}: {}", what, detail);
+
+    if config.debug_errors {
+        five_hundred(format!("couldn't render {}: {}", what, detail))
+    } else {
+        five_hundred(format!("couldn't render {}", what))
+    }
+}
+
+fn four_hundred<B: Display>(body: B) -> Response<String> {
+    response_with_status(StatusCode::BAD_REQUEST, body)
+}
+
+fn bad_gateway<B: Display>(body: B) -> Response<String> {
+    response_with_status(StatusCode::BAD_GATEWAY, body)
+}
+
+fn compressible(response: &Response<warp::hyper::Body>) -> bool {
+    if response.headers().contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+
+    match response.headers().get(CONTENT_TYPE) {
+        Some(content_type) => {
+            let content_type = content_type.to_str().unwrap_or_default();
+            content_type.starts_with("text/")
+                || content_type.starts_with("application/json")
+                || content_type.starts_with("application/xml")
+                || content_type.starts_with("application/atom+xml")
+                || content_type.starts_with("application/javascript")
+                || content_type.starts_with("image/svg+xml")
+        }
+        None => true,
+    }
+}
+
+async fn compress(
+    config: &Config,
+    accept_encoding: Option<String>,
+    response: Response<warp::hyper::Body>,
+) -> Response<warp::hyper::Body> {
+    let Some(accept_encoding) = accept_encoding else {
+        return response;
+    };
+
+    let accepts = |encoding: &str| {
+        accept_encoding.split(',').any(|accepted| {
+            let mut params = accepted.split(';').map(str::trim);
+            params.next() == Some(encoding) && !params.any(|param| param == "q=0")
+        })
+    };
+    let encoding = if accepts("gzip") {
+        "gzip"
+    } else if accepts("deflate") {
+        "deflate"
+    } else {
+        return response;
+    };
+
+    if !config.compression || !compressible(&response) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match warp::hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => return five_hundred(err).map(Into::into),
+    };
+
+    if body.len() < config.compression_min_bytes {
+        return Response::from_parts(parts, body.into());
+    }
+
+    let compressed = if encoding == "gzip" {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).and_then(|_| encoder.finish())
+    } else {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).and_then(|_| encoder.finish())
+    };
+    let Ok(compressed) = compressed else {
+        return Response::from_parts(parts, body.into());
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    Response::from_parts(parts, compressed.into())
+}
+
+#[derive(Serialize)]
+struct ContentStats {
+    words: usize,
+    reading_minutes: usize,
+}
+
+impl Display for ContentStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} words, ~{} min read",
+            self.words, self.reading_minutes
+        )
+    }
+}
+
+/// 128 bits from the OS, hex encoded.
+fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("the OS random number generator failed");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn split_front_matter(content: &str) -> (&str, &str) {
+    for fence in ["+++", "---"] {
+        if let Some(rest) = content.strip_prefix(fence) {
+            if let Some(end) = rest.find(&format!("\n{}", fence)) {
+                let mut split = fence.len() + end + 1 + fence.len();
+                if content[split..].starts_with("\r\n") {
+                    split += 2;
+                } else if content[split..].starts_with('\n') {
+                    split += 1;
+                }
+                return content.split_at(split);
+            }
+        }
+    }
+    ("", content)
+}
+
+fn strip_front_matter(content: &str) -> &str {
+    split_front_matter(content).1
+}
+
+fn validate_front_matter(front_matter: &str) -> Result<(), String> {
+    let front_matter = front_matter.trim();
+    if front_matter.is_empty() {
+        return Ok(());
+    }
+
+    for fence in ["+++", "---"] {
+        let Some(inner) = front_matter
+            .strip_prefix(fence)
+            .and_then(|rest| rest.strip_suffix(fence))
+        else {
+            continue;
+        };
+
+        return if fence == "+++" {
+            toml::from_str::<toml::Table>(inner)
+                .map(|_| ())
+                .map_err(|err| format!("invalid TOML front matter: {}", err))
+        } else {
+            serde_yaml::from_str::<serde_yaml::Value>(inner)
+                .map(|_| ())
+                .map_err(|err| format!("invalid YAML front matter: {}", err))
+        };
+    }
+
+    Err(String::from("front matter must be fenced by +++ or ---"))
+}
+
+fn join_front_matter(front_matter: &str, body: &str) -> String {
+    let front_matter = front_matter.trim_end();
+    if front_matter.is_empty() {
+        String::from(body)
+    } else {
+        format!("{}\n{}", front_matter, body)
+    }
+}
+
+/// Reads a file in blog_dir as file_encoding, or UTF-8 if that isn't set. Drafts and autosaves
+/// live outside blog_dir and are always UTF-8.
+async fn read_blog_file(config: &Config, path: &Path) -> std::io::Result<String> {
+    let bytes = tokio::fs::read(path).await?;
+    let encoding = match config.file_encoding {
+        Some(encoding) if path.starts_with(&config.blog_dir) => encoding,
+        _ => UTF_8,
+    };
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes)
+        .map(String::from)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} isn't valid {}", path.display(), encoding.name()),
+            )
+        })
+}
+
+/// Refuses content with characters file_encoding can't represent instead of writing replacements.
+#[allow(clippy::result_large_err)]
+fn encode_content(config: &Config, content: &str) -> Result<Vec<u8>, Response<String>> {
+    let Some(encoding) = config.file_encoding else {
+        return Ok(content.as_bytes().to_vec());
+    };
+    let (bytes, _, had_errors) = encoding.encode(content);
+    if had_errors {
+        return Err(four_hundred(format!(
+            "content has characters that can't be written as {}",
+            encoding.name()
+        )));
+    }
+    Ok(bytes.into_owned())
+}
+
+fn content_stats(config: &Config, content: &str) -> ContentStats {
+    let words = if config.count_prose_only {
+        let markup = Regex::new(r"(?s)```.*?```|<[^>]*>|\]\([^)]*\)").unwrap();
+        markup
+            .replace_all(strip_front_matter(content), " ")
+            .split_whitespace()
+            .filter(|word| word.chars().any(char::is_alphanumeric))
+            .count()
+    } else {
+        content.split_whitespace().count()
+    };
+
+    ContentStats {
+        words,
+        reading_minutes: words.div_ceil(config.words_per_minute.max(1)),
+    }
+}
+
+/// None without an Authorization header. Credentials that don't check out are a 401 rather than
+/// falling back to whatever an anonymous user may do.
+#[allow(clippy::result_large_err)]
+fn authenticate(
+    config: &Config,
+    authorization: Option<&str>,
+) -> Result<Option<String>, Response<String>> {
+    let Some(authorization) = authorization else {
+        return Ok(None);
+    };
+    check_credentials(config, authorization)
+        .map(Some)
+        .ok_or_else(unauthorized)
+}
+
+fn check_credentials(config: &Config, authorization: &str) -> Option<String> {
+    let credentials = authorization.strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+
+    let expected = config.users.get(user)?;
+    bool::from(expected.as_bytes().ct_eq(password.as_bytes())).then(|| user.into())
+}
+
+fn unauthorized() -> Response<String> {
+    Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Basic realm=\"editor\"")
+        .body(String::from("authentication required"))
+        .unwrap()
+}
+
+#[allow(clippy::result_large_err)]
+fn require_user(config: &Config, authorization: Option<&str>) -> Result<String, Response<String>> {
+    authenticate(config, authorization)?.ok_or_else(unauthorized)
+}
+
+/// What a user may change. Anyone can look, and admins are configured separately.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    Reader,
+    Editor,
+    Publisher,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Reader => "reader",
+            Role::Editor => "editor",
+            Role::Publisher => "publisher",
+        })
+    }
+}
+
+/// Without default_role, only a deployment with no users at all lets everyone publish.
+fn role(config: &Config, user: Option<&str>) -> Role {
+    user.and_then(|user| config.roles.get(user))
+        .copied()
+        .or(config.default_role)
+        .unwrap_or(if config.users.is_empty() {
+            Role::Publisher
+        } else {
+            Role::Reader
+        })
+}
+
+#[allow(clippy::result_large_err)]
+fn require_role(
+    config: &Config,
+    user: Option<&str>,
+    required: Role,
+    action: &str,
+) -> Result<(), Response<String>> {
+    if role(config, user) >= required {
+        return Ok(());
+    }
+
+    // logging in might help
+    let Some(user) = user else {
+        return require_user(config, None).map(|_| ());
+    };
+    Err(response_with_status(
+        StatusCode::FORBIDDEN,
+        format!(
+            "{} can't {}, that takes the {} role",
+            user, action, required
+        ),
+    ))
+}
+
+#[allow(clippy::result_large_err)]
+fn require_admin(config: &Config, authorization: Option<&str>) -> Result<String, Response<String>> {
+    let user = require_user(config, authorization)?;
+
+    if !config.admins.contains(&user) {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            format!("{} is not an admin", user),
+        ));
+    }
+
+    Ok(user)
+}
+
+/// The blog's page at blog_url, retried on server errors and read up to max_blog_response_bytes.
+async fn fetch_blog_page(
+    config: &Config,
+    state: &State,
+    forwarded: &Forwarded,
+    blog_url: &Url,
+    path: &str,
+) -> Result<String, Response<String>> {
+    let mut attempt = 1;
+    let mut blog_response = loop {
+        let started = Instant::now();
+        let response = state.client.get(blog_url.clone()).send().await;
+        METRICS
+            .blog_request_duration
+            .observe(started.elapsed().as_secs_f64());
+
+        let retry = match response {
+            Ok(response) if response.status().is_server_error() => {
+                format!("{} returned {}", blog_url, response.status())
+            }
+            Ok(response) => break response,
+            Err(err) if err.is_connect() || err.is_timeout() => {
+                format!("couldn't reach {}: {}", blog_url, err)
+            }
+            Err(err) if err.is_redirect() => {
+                return Err(bad_gateway(format!(
+                    "{} redirected more than {} times",
+                    blog_url, config.max_redirects
+                )))
+            }
+            Err(err) => return Err(bad_gateway(format!("couldn't reach {}: {}", blog_url, err))),
+        };
+
+        if attempt >= config.blog_fetch_attempts {
+            return Err(bad_gateway(retry));
+        }
+
+        let backoff = Duration::from_millis(config.blog_fetch_backoff_ms * 2u64.pow(attempt - 1));
+        println!(
+            "{}, retrying in {:?} (attempt {}/{})",
+            retry, backoff, attempt, config.blog_fetch_attempts
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    };
+
+    if blog_response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(Response::builder()
+            .header("Content-Type", "text/html")
+            .body(format!(
+                "<head><meta http-equiv=\"Refresh\" content=\"0; URL={}\"></head>",
+                route_url(
+                    config,
+                    &forwarded.url,
+                    &format!("{}/{}", PUBLISH_ROUTE, path.trim_start_matches('/'))
+                )
+            ))
+            .unwrap());
+    }
+
+    if blog_response.status().is_redirection() {
+        return Err(bad_gateway(format!(
+            "{} redirected to {} and follow_redirects is off",
+            blog_url,
+            blog_response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .unwrap_or("nowhere")
+        )));
+    }
+
+    if !blog_response.status().is_success() {
+        return Err(bad_gateway(format!(
+            "{} returned {}",
+            blog_url,
+            blog_response.status()
+        )));
+    }
+
+    if blog_response
+        .content_length()
+        .is_some_and(|length| length > config.max_blog_response_bytes as u64)
+    {
+        return Err(bad_gateway(format!(
+            "{} is over max_blog_response_bytes ({})",
+            blog_url, config.max_blog_response_bytes
+        )));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = blog_response.chunk().await.map_err(bad_gateway)? {
+        if body.len() + chunk.len() > config.max_blog_response_bytes {
+            return Err(bad_gateway(format!(
+                "{} is over max_blog_response_bytes ({})",
+                blog_url, config.max_blog_response_bytes
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Cached per request path for path_cache_ttl_secs, containment check and all.
+async fn path_to_file(
+    config: &Config,
+    state: &State,
+    forwarded: &Forwarded,
+    path: &str,
+) -> Result<PathBuf, Response<String>> {
+    resolve_path(config, state, forwarded, path, false).await
+}
+
+/// Like path_to_file, but a file the blog page refers to that doesn't exist yet resolves too, as
+/// long as the directory it would go in does. GET /edit offers to create it and POST /edit does.
+async fn path_to_new_or_existing_file(
+    config: &Config,
+    state: &State,
+    forwarded: &Forwarded,
+    path: &str,
+) -> Result<PathBuf, Response<String>> {
+    resolve_path(config, state, forwarded, path, true).await
+}
+
+async fn resolve_path(
+    config: &Config,
+    state: &State,
+    forwarded: &Forwarded,
+    path: &str,
+    new_file: bool,
+) -> Result<PathBuf, Response<String>> {
+    if let Some(actual_path) = state.cached_path(config, path) {
+        return Ok(actual_path);
+    }
+
+    let blog_url = config.blog_url.join(path).unwrap();
+    let timeout = Duration::from_secs(config.blog_fetch_timeout_secs);
+    let blog_text = tokio::time::timeout(
+        timeout,
+        fetch_blog_page(config, state, forwarded, &blog_url, path),
+    )
+    .await
+    .map_err(|_| bad_gateway(format!("{} didn't respond within {:?}", blog_url, timeout)))??;
+
+    let relative_path = match config
+        .path_regex
+        .iter()
+        .find_map(|regex| regex.captures(&blog_text)?.get(1))
+    {
+        Some(relative_path) => relative_path.as_str(),
+        None => {
+            return Err(five_hundred(format!(
+                "nothing matching {} in {}",
+                config
+                    .path_regex
+                    .iter()
+                    .map(|regex| regex.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" or "),
+                blog_text
+            )))
+        }
+    };
+
+    let mut page_path = config.blog_dir.clone();
+    page_path.push(relative_path);
+    let actual_path = match page_path.canonicalize() {
+        Ok(actual_path) => actual_path,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && new_file => {
+            match (
+                page_path.parent().map(Path::canonicalize),
+                page_path.file_name(),
+            ) {
+                (Some(Ok(parent)), Some(file_name)) => parent.join(file_name),
+                _ => {
+                    return Err(response_with_status(
+                        StatusCode::NOT_FOUND,
+                        format!(
+                            "the blog page refers to {}, which isn't in an existing directory",
+                            page_path.display()
+                        ),
+                    ))
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(response_with_status(
+                StatusCode::NOT_FOUND,
+                format!(
+                    "the blog page refers to {}, which doesn't exist",
+                    page_path.display()
+                ),
+            ))
+        }
+        Err(err) => return Err(five_hundred(err)),
+    };
+
+    let actual_path = if actual_path.is_dir() {
+        let index_path = actual_path.join(&config.directory_index);
+        match index_path.canonicalize() {
+            Ok(index_path) => index_path,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(four_hundred(format!(
+                    "{} is a directory without {}",
+                    actual_path.display(),
+                    config.directory_index
+                )))
+            }
+            Err(err) => return Err(five_hundred(err)),
+        }
+    } else {
+        actual_path
+    };
+
+    if !actual_path.starts_with(&config.blog_dir) {
+        return Err(outside_allowed(
+            Some(&forwarded.client),
+            &actual_path,
+            &config.blog_dir,
+        ));
+    }
+
+    check_editable(config, &actual_path)?;
+    if actual_path.is_file() {
+        state.cache_path(config, path, &actual_path);
+    }
+
+    Ok(actual_path)
+}
+
+#[allow(clippy::result_large_err)]
+fn check_editable(config: &Config, path: &Path) -> Result<(), Response<String>> {
+    check_editable_prefix(config, path)?;
+
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !config.editable_extensions.contains(&extension) {
+        return Err(four_hundred(format!(
+            "can't edit .{} files, allowed extensions are {}",
+            extension,
+            config.editable_extensions.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn check_editable_prefix(config: &Config, path: &Path) -> Result<(), Response<String>> {
+    let relative_path = path.strip_prefix(&config.blog_dir).unwrap_or(path);
+    if !config.editable_path_prefixes.is_empty()
+        && !config
+            .editable_path_prefixes
+            .iter()
+            .any(|prefix| relative_path.starts_with(prefix))
+    {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            format!("{} isn't in an editable directory", relative_path.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn check_protected(config: &Config, path: &Path) -> Result<(), Response<String>> {
+    let relative_path = path.strip_prefix(&config.blog_dir).unwrap_or(path);
+    if config.protected_paths.is_match(relative_path) {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            format!("{} is protected", relative_path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Logs and counts a path that resolved outside of base. The client only gets told no.
+fn outside_allowed(client: Option<&str>, actual_path: &Path, base: &Path) -> Response<String> {
+    println!(
+        "WARNING: {} requested {}, which is outside {}",
+        client.unwrap_or("a client"),
+        actual_path.display(),
+        base.display()
+    );
+    METRICS.escapes.inc();
+    response_with_status(StatusCode::FORBIDDEN, "path outside allowed directory")
+}
+
+#[allow(clippy::result_large_err)]
+fn contained_path(base: &Path, relative: &str) -> Result<PathBuf, Response<String>> {
+    let actual_path = normalize_path(base.join(relative).as_path());
+    if !actual_path.starts_with(base) {
+        return Err(outside_allowed(None, &actual_path, base));
+    }
+    Ok(actual_path)
+}
+
+/// Resolve a path relative to blog_dir to an existing, editable file inside it.
+#[allow(clippy::result_large_err)]
+fn blog_file(config: &Config, path: &str) -> Result<PathBuf, Response<String>> {
+    let actual_path = contained_path(&config.blog_dir, path.trim_start_matches('/'))?
+        .canonicalize()
+        .map_err(|_| {
+            response_with_status(StatusCode::NOT_FOUND, format!("no such file {}", path))
+        })?;
+    if !actual_path.starts_with(&config.blog_dir) {
+        return Err(outside_allowed(None, &actual_path, &config.blog_dir));
+    }
+    check_editable(config, &actual_path)?;
+    Ok(actual_path)
+}
+
+async fn create_new(path: &Path) -> Result<(), Response<String>> {
+    let path = path.to_path_buf();
+    let created = tokio::task::spawn_blocking(move || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+    })
+    .await
+    .map_err(five_hundred)?;
+
+    match created {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            Err(four_hundred("already exists"))
+        }
+        Err(err) => Err(five_hundred(err)),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn draft_path(config: &Config, actual_path: &Path) -> Result<PathBuf, Response<String>> {
+    let Some(drafts_dir) = &config.drafts_dir else {
+        return Err(four_hundred("drafts are not configured"));
+    };
+    Ok(drafts_dir.join(actual_path.strip_prefix(&config.blog_dir).unwrap()))
+}
+
+async fn save_draft(
+    config: &Config,
+    actual_path: &Path,
+    content: &str,
+) -> Result<PathBuf, Response<String>> {
+    let draft_path = draft_path(config, actual_path)?;
+    if let Some(parent) = draft_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(five_hundred)?;
+    }
+    tokio::fs::write(&draft_path, normalize_content(config, content))
+        .await
+        .map_err(five_hundred)?;
+    Ok(draft_path)
+}
+
+async fn load_draft(config: &Config, actual_path: &Path) -> Result<String, Response<String>> {
+    let draft_path = draft_path(config, actual_path)?;
+    tokio::fs::read_to_string(&draft_path)
+        .await
+        .map_err(|_| four_hundred(format!("no draft of {}", actual_path.display())))
+}
+
+#[allow(clippy::result_large_err)]
+fn autosave_path(config: &Config, actual_path: &Path) -> Result<PathBuf, Response<String>> {
+    let mut autosave_path = draft_path(config, actual_path)?.into_os_string();
+    autosave_path.push(".autosave");
+    Ok(autosave_path.into())
+}
+
+async fn newer_draft(config: &Config, actual_path: &Path) -> Option<PathBuf> {
+    newer_than(draft_path(config, actual_path).ok()?, actual_path).await
+}
+
+async fn newer_than(draft_path: PathBuf, actual_path: &Path) -> Option<PathBuf> {
+    let draft_modified = tokio::fs::metadata(&draft_path)
+        .await
+        .ok()?
+        .modified()
+        .ok()?;
+    match tokio::fs::metadata(actual_path)
+        .await
+        .and_then(|metadata| metadata.modified())
+    {
+        Ok(modified) if modified >= draft_modified => None,
+        _ => Some(draft_path),
+    }
+}
+
+async fn remove_draft(config: &Config, actual_path: &Path) {
+    if let Ok(draft_path) = draft_path(config, actual_path) {
+        let _ = tokio::fs::remove_file(draft_path).await;
+    }
+    if let Ok(autosave_path) = autosave_path(config, actual_path) {
+        let _ = tokio::fs::remove_file(autosave_path).await;
+    }
+}
+
+async fn dead_links(config: &Config, relative_to: Option<&Path>, content: &str) -> Vec<String> {
+    let mut dead = Vec::new();
+
+    for pattern in &config.link_patterns {
+        for captures in pattern.captures_iter(content) {
+            let Some(link) = captures.get(1).map(|link| link.as_str()) else {
+                continue;
+            };
+
+            if link.starts_with('#') || link.starts_with("//") || link.contains(':') {
+                continue;
+            }
+
+            let target = link.split(['#', '?']).next().unwrap_or_default();
+            if target.is_empty() {
+                continue;
+            }
+
+            let candidates = if let Some(absolute) = target.strip_prefix('/') {
+                vec![
+                    config.blog_dir.join(absolute),
+                    config.blog_build_dir.join(absolute),
+                    config.blog_build_dir.join(absolute).join("index.html"),
+                ]
+            } else {
+                let base = relative_to
+                    .and_then(|path| path.parent())
+                    .unwrap_or(&config.blog_dir);
+                vec![base.join(target), config.blog_build_dir.join(target)]
+            };
+
+            let mut found = false;
+            for candidate in candidates {
+                let candidate = normalize_path(&candidate);
+                if (candidate.starts_with(&config.blog_dir)
+                    || candidate.starts_with(&config.blog_build_dir))
+                    && tokio::fs::try_exists(&candidate).await.unwrap_or(false)
+                {
+                    found = true;
+                    break;
+                }
+            }
+
+            if !found && !dead.iter().any(|dead| dead == link) {
+                dead.push(String::from(link));
+            }
+        }
+    }
+
+    dead
+}
+
+async fn check_links(
+    config: &Config,
+    relative_to: Option<&Path>,
+    content: &str,
+) -> Result<(), Response<String>> {
+    if !config.block_on_dead_links {
+        return Ok(());
+    }
+
+    let dead = dead_links(config, relative_to, content).await;
+    if dead.is_empty() {
+        Ok(())
+    } else {
+        Err(four_hundred(format!("dead links:\n{}", dead.join("\n"))))
+    }
+}
+
+/// Runs a command in blog_dir. The writes lock keeps this editor from racing itself, but something
+/// else (a cron `git gc`, someone at a shell) can still hold a git lock for a moment, so commands
+/// that fail on one are retried with backoff.
+async fn command_stdout(
+    config: &Config,
+    args: impl Iterator<Item = &str>,
+) -> Result<String, Response<String>> {
+    let args = args.collect::<Vec<&str>>();
+    let mut attempt = 1;
+    loop {
+        match command_stdout_in(&config.blog_dir, args.iter().copied()).await {
+            Err(err) if git_locked(err.body()) => {
+                if attempt >= config.git_lock_attempts {
+                    return Err(response_with_status(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        format!(
+                            "the repository was locked by another process, gave up after {} attempts\n\n{}",
+                            attempt,
+                            err.body()
+                        ),
+                    ));
+                }
+
+                let backoff =
+                    Duration::from_millis(config.git_lock_backoff_ms * 2u64.pow(attempt - 1));
+                println!(
+                    "{} is locked by another process, retrying in {:?} (attempt {}/{})",
+                    config.blog_dir.display(),
+                    backoff,
+                    attempt,
+                    config.git_lock_attempts
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// What git prints when index.lock, HEAD.lock, etc. already exist.
+fn git_locked(output: &str) -> bool {
+    output.contains(".lock': File exists")
+}
+
+async fn command_stdout_in(
+    dir: &Path,
+    args: impl Iterator<Item = &str>,
+) -> Result<String, Response<String>> {
+    let args = args.collect::<Vec<&str>>();
+    let mut command = Command::new(args[0]);
+    for arg in &args[1..] {
+        command.arg(arg);
+    }
+
+    command.current_dir(dir);
+    let output = command.output().await.map_err(five_hundred)?;
+
+    if !output.status.success() {
+        let all_output = String::from("failed: ")
+            + &args.join(" ")
+            + "\nstdout:\n"
+            + &String::from_utf8_lossy(&output.stdout)
+            + "\nstderr:\n"
+            + &String::from_utf8_lossy(&output.stderr);
+        return Err(five_hundred(all_output));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into())
+}
+
+/// Runs stage_revision, create_revision and reset_command in a throwaway copy of blog_dir, so a
+/// broken command shows up at deploy time instead of as a half-made commit on the first edit.
+async fn selftest(config: &Config) -> Result<(), Response<String>> {
+    // a name nobody could have guessed and created first, create_dir fails rather than reuse it
+    let dir = std::env::temp_dir().join(format!("editor-selftest-{}", random_token()));
+    tokio::fs::create_dir(&dir).await.map_err(five_hundred)?;
+    let result = async {
+        copy_recursive(&config.blog_dir, &dir, false).await?;
+
+        let committed = dir.join("editor-selftest.md");
+        tokio::fs::write(&committed, "self-test\n")
+            .await
+            .map_err(five_hundred)?;
+        let committed = committed.display().to_string();
+        let stage = command_stdout_in(
+            &dir,
+            config
+                .stage_revision
+                .iter()
+                .map(|s| s.as_str())
+                .chain([committed.as_str()]),
+        )
+        .await?;
+        println!("stage_revision ok\n{}", stage);
+        let commit = command_stdout_in(
+            &dir,
+            config
+                .create_revision
+                .iter()
+                .map(|s| s.as_str())
+                .chain(["editor self-test"]),
+        )
+        .await?;
+        println!("create_revision ok\n{}", commit);
+
+        // what a failed edit leaves behind
+        let stray = dir.join("editor-selftest-stray.md");
+        tokio::fs::write(&stray, "self-test\n")
+            .await
+            .map_err(five_hundred)?;
+        let reset =
+            command_stdout_in(&dir, config.reset_command.iter().map(|s| s.as_str())).await?;
+        println!("reset_command ok\n{}", reset);
+        if stray.exists() {
+            println!("WARNING: reset_command left a new file behind, failed edits of new files won't be cleaned up");
+        }
+
+        Ok(())
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    result
+}
+
+/// Copies permissions along with contents, and modification times if preserve_mtime is set.
+async fn copy_recursive(
+    from: &Path,
+    to: &Path,
+    preserve_mtime: bool,
+) -> Result<(), Response<String>> {
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    let mut dirs = Vec::new();
+
+    while let Some((from, to)) = pending.pop() {
+        let metadata = tokio::fs::metadata(&from).await.map_err(five_hundred)?;
+        if !metadata.is_dir() {
+            tokio::fs::copy(&from, &to).await.map_err(five_hundred)?;
+            tokio::fs::set_permissions(&to, metadata.permissions())
+                .await
+                .map_err(five_hundred)?;
+            if preserve_mtime {
+                let modified = metadata.modified().map_err(five_hundred)?;
+                tokio::task::spawn_blocking(move || {
+                    std::fs::File::open(to)?.set_modified(modified)
+                })
+                .await
+                .map_err(five_hundred)?
+                .map_err(five_hundred)?;
+            }
+            continue;
+        }
+
+        tokio::fs::create_dir_all(&to).await.map_err(five_hundred)?;
+        dirs.push((to.clone(), metadata.permissions()));
+        let mut entries = tokio::fs::read_dir(&from).await.map_err(five_hundred)?;
+        while let Some(entry) = entries.next_entry().await.map_err(five_hundred)? {
+            pending.push((entry.path(), to.join(entry.file_name())));
+        }
+    }
+
+    // after everything's copied in case a directory is read-only
+    for (dir, permissions) in dirs.into_iter().rev() {
+        tokio::fs::set_permissions(&dir, permissions)
+            .await
+            .map_err(five_hundred)?;
+    }
+
+    Ok(())
+}
+
+async fn backup(config: &Config, path: &Path) -> Result<(), Response<String>> {
+    let Some(backup_dir) = &config.backup_dir else {
+        return Ok(());
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let snapshot = backup_dir.join(format!(
+        "{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        name
+    ));
+    tokio::fs::create_dir_all(&snapshot)
+        .await
+        .map_err(five_hundred)?;
+    copy_recursive(
+        path,
+        &snapshot.join(name.as_ref()),
+        config.backup_preserve_mtime,
+    )
+    .await?;
+    println!("backed up {} to {}", path.display(), snapshot.display());
+
+    let mut snapshots = Vec::new();
+    let mut entries = tokio::fs::read_dir(backup_dir)
+        .await
+        .map_err(five_hundred)?;
+    while let Some(entry) = entries.next_entry().await.map_err(five_hundred)? {
+        snapshots.push(entry.path());
+    }
+    snapshots.sort();
+
+    // 0 keeps every snapshot
+    let prune = match config.backup_retention {
+        0 => 0,
+        retention => snapshots.len().saturating_sub(retention),
+    };
+    for old in &snapshots[..prune] {
+        tokio::fs::remove_dir_all(old).await.map_err(five_hundred)?;
+    }
+
+    Ok(())
+}
+
+const BUILD_STATUS_OUTPUT_CHARS: usize = 4000;
+
+#[derive(Serialize, Clone, Default)]
+struct BuildStatus {
+    in_progress: bool,
+    started_at: Option<DateTime<Utc>>,
+    duration_ms: Option<u128>,
+    success: Option<bool>,
+    output: String,
+}
+
+async fn rebuild(config: &Config, state: &State) -> Result<String, Response<String>> {
+    {
+        let mut build_status = state.build_status.write().unwrap();
+        build_status.in_progress = true;
+        build_status.started_at = Some(Utc::now());
+    }
+
+    let started = Instant::now();
+    let blog_build_output =
+        match command_stdout(config, config.build_command.iter().map(|s| s.as_str())).await {
+            Ok(output) => check_build(config).await.map(|_| output),
+            Err(err) => Err(err),
+        };
+    METRICS
+        .build_duration
+        .observe(started.elapsed().as_secs_f64());
+    METRICS.rebuilds.inc();
+
+    {
+        let mut build_status = state.build_status.write().unwrap();
+        let output = match &blog_build_output {
+            Ok(output) => output.as_str(),
+            Err(err) => err.body().as_str(),
+        };
+        let skip = output
+            .chars()
+            .count()
+            .saturating_sub(BUILD_STATUS_OUTPUT_CHARS);
+        build_status.in_progress = false;
+        build_status.duration_ms = Some(started.elapsed().as_millis());
+        build_status.success = Some(blog_build_output.is_ok());
+        build_status.output = output.chars().skip(skip).collect();
+    }
+    let blog_build_output = blog_build_output?;
+
+    if tokio::fs::try_exists(&config.dest_dir)
+        .await
+        .map_err(five_hundred)?
+    {
+        if config.backup_dest_dir {
+            backup(config, &config.dest_dir).await?;
+        }
+        tokio::fs::remove_dir_all(&config.dest_dir)
+            .await
+            .map_err(five_hundred)?;
+    }
+
+    Ok(blog_build_output)
+}
+
+/// build_command can succeed without building anything useful, check before dest_dir is replaced.
+async fn check_build(config: &Config) -> Result<(), Response<String>> {
+    let mut entries = tokio::fs::read_dir(&config.blog_build_dir)
+        .await
+        .map_err(five_hundred)?;
+    if entries.next_entry().await.map_err(five_hundred)?.is_none() {
+        return Err(five_hundred(format!(
+            "build succeeded but {} is empty, not replacing {}",
+            config.blog_build_dir.display(),
+            config.dest_dir.display()
+        )));
+    }
+
+    if let Some(build_sentinel) = &config.build_sentinel {
+        let sentinel = config.blog_build_dir.join(build_sentinel);
+        if !tokio::fs::try_exists(&sentinel).await.unwrap_or(false) {
+            return Err(five_hundred(format!(
+                "build succeeded but {} is missing, not replacing {}",
+                sentinel.display(),
+                config.dest_dir.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+async fn reset_if_err<T>(
+    config: &Config,
+    state: &State,
+    f: impl Future<Output = Result<T, Response<String>>>,
+) -> Result<T, Response<String>> {
+    match f.await {
+        Ok(ok) => Ok(ok),
+        Err(mut err) => {
+            match command_stdout(config, config.reset_command.iter().map(|s| s.as_str())).await {
+                Ok(ok) => err
+                    .body_mut()
+                    .push_str(&format!("\n\nhad to reset\n\n{}", ok)),
+                Err(err2) => {
+                    println!("reset failed, refusing writes until cleared");
+                    *state.degraded.write().unwrap() = Some(format!(
+                        "{}: reset failed\n\n{}",
+                        Utc::now().to_rfc3339(),
+                        err2.body()
+                    ));
+                    err.body_mut()
+                        .push_str(&format!("\n\nfailed resetting\n\n{}", err2.body()))
+                }
+            }
+
+            if let Some(status_command) = &config.status_command {
+                match command_stdout(config, status_command.iter().map(|s| s.as_str())).await {
+                    Ok(status) if status.trim().is_empty() => {}
+                    Ok(status) => {
+                        println!(
+                            "reset did NOT leave a clean tree, refusing writes until cleared:\n{}",
+                            status
+                        );
+                        *state.degraded.write().unwrap() = Some(format!(
+                            "{}: reset did not leave a clean tree\n\n{}",
+                            Utc::now().to_rfc3339(),
+                            status
+                        ));
+                        err.body_mut().push_str(&format!(
+                            "\n\nWARNING: reset did NOT leave a clean tree, writes are refused until an admin clears it\n\n{}",
+                            status
+                        ));
+                    }
+                    Err(err2) => err.body_mut().push_str(&format!(
+                        "\n\ncouldn't check tree status\n\n{}",
+                        err2.body()
+                    )),
+                }
+            }
+
+            Err(err)
+        }
+    }
+}
+
+fn normalize_content(config: &Config, content: &str) -> String {
+    let mut content = if config.normalize_line_endings {
+        content.replace("\r\n", "\n")
+    } else {
+        String::from(content)
+    };
+
+    if config.strip_trailing_whitespace {
+        content = content
+            .split('\n')
+            .map(|line| match line.strip_suffix('\r') {
+                Some(line) => format!("{}\r", line.trim_end()),
+                None => String::from(line.trim_end()),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    if config.ensure_trailing_newline {
+        let line_ending = if content.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        content = format!("{}{}", content.trim_end_matches(['\r', '\n']), line_ending);
+    }
+
+    content
+}
+
+/// Normalizes, writes, formats and validates content, returning what ended up in the file and what
+/// format_command printed.
+async fn write_content(
+    config: &Config,
+    actual_path: &Path,
+    content: &str,
+) -> Result<(String, String), Response<String>> {
+    let mut content = normalize_content(config, content);
+    check_content_bytes(config, content.len())?;
+
+    match tokio::fs::write(&actual_path, encode_content(config, &content)?).await {
+        Ok(_) => {}
+        Err(_) => return Err(five_hundred("couldn't write")),
+    }
+
+    let format_command = actual_path
+        .extension()
+        .and_then(|extension| config.format_command.get(&*extension.to_string_lossy()));
+    let mut format_output = String::new();
+    if let Some(format_command) = format_command {
+        let path = format!("{}", actual_path.display());
+        format_output = command_stdout(
+            config,
+            format_command
+                .iter()
+                .map(|s| s.as_str())
+                .chain([path.as_str()]),
+        )
+        .await
+        .map_err(|err| four_hundred(format!("formatting {}", err.body())))?;
+        // the formatter may have rewritten the file
+        content = read_blog_file(config, actual_path)
+            .await
+            .map_err(five_hundred)?;
+    }
+
+    if let Some(validate_command) = &config.validate_command {
+        let path = format!("{}", actual_path.display());
+        command_stdout(
+            config,
+            validate_command
+                .iter()
+                .map(|s| s.as_str())
+                .chain([path.as_str()]),
+        )
+        .await
+        .map_err(|err| four_hundred(format!("validation {}", err.body())))?;
+    }
+
+    Ok((content, format_output))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn set_content_with_revision(
+    config: &'static Config,
+    state: &'static State,
+    actual_path: &Path,
+    content: &str,
+    note: Option<&str>,
+    user: Option<&str>,
+    skip_build: bool,
+    branch: Option<&str>,
+) -> Result<(String, RevisionOutput), Response<String>> {
+    let (content, format_output) = write_content(config, actual_path, content).await?;
+    let message = commit_message(config, "edit", &[actual_path], note, user)?;
+    let mut revision_output =
+        create_revision(config, state, &[actual_path], message, skip_build, branch).await?;
+    revision_output.format = format_output;
+    Ok((content, revision_output))
+}
+
+#[allow(clippy::result_large_err)]
+fn check_content_bytes(config: &Config, bytes: usize) -> Result<(), Response<String>> {
+    if bytes > config.max_content_bytes {
+        return Err(response_with_status(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "content is {} bytes, the limit is {}",
+                bytes, config.max_content_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn check_note(config: &Config, note: Option<&str>) -> Result<(), Response<String>> {
+    if config.require_note && note.is_none_or(|note| note.trim().is_empty()) {
+        return Err(four_hundred("a revision note is required"));
+    }
+    Ok(())
+}
+
+/// skip_build=on commits without building, for sites that are built elsewhere or in batches with
+/// POST /admin/rebuild.
+#[allow(clippy::result_large_err)]
+fn skip_build(config: &Config, form: &HashMap<String, String>) -> Result<bool, Response<String>> {
+    if form.get("skip_build").map(|s| s.as_str()) != Some("on") {
+        return Ok(false);
+    }
+    if !config.allow_skip_build {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            "skipping the build isn't allowed",
+        ));
+    }
+    Ok(true)
+}
+
+fn allow_branch(config: &Config) -> bool {
+    config.branch_command.is_some() && config.checkout_command.is_some()
+}
+
+/// branch=<name> commits onto that branch instead of the current one, to be reviewed and merged
+/// with POST /admin/promote.
+#[allow(clippy::result_large_err)]
+fn branch<'form>(
+    config: &Config,
+    form: &'form HashMap<String, String>,
+) -> Result<Option<&'form str>, Response<String>> {
+    let Some(branch) = form
+        .get("branch")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    else {
+        return Ok(None);
+    };
+    if !allow_branch(config) {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            "committing to a branch isn't set up",
+        ));
+    }
+    check_branch_name(branch)?;
+    Ok(Some(branch))
+}
+
+/// Stricter than git needs, but nothing passed to checkout_command or promote_command can be
+/// taken for a flag or a revision expression.
+#[allow(clippy::result_large_err)]
+fn check_branch_name(branch: &str) -> Result<(), Response<String>> {
+    let valid = !branch.starts_with(['-', '/', '.'])
+        && !branch.ends_with(['/', '.'])
+        && !branch.ends_with(".lock")
+        && !branch.contains("..")
+        && !branch.contains("//")
+        && branch
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+    if !valid {
+        return Err(four_hundred(format!("invalid branch name {}", branch)));
+    }
+    Ok(())
+}
+
+/// The template gets every path relative to blog_dir as `paths`, and `path` with them joined by
+/// commas.
+#[allow(clippy::result_large_err)]
+fn commit_message(
+    config: &Config,
+    action: &str,
+    actual_paths: &[&Path],
+    note: Option<&str>,
+    user: Option<&str>,
+) -> Result<String, Response<String>> {
+    let paths = actual_paths
+        .iter()
+        .map(|actual_path| {
+            actual_path
+                .strip_prefix(&config.blog_dir)
+                .unwrap_or(actual_path)
+                .display()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    let path = paths.join(", ");
+    println!(
+        "{} ({}) {} {}",
+        user.unwrap_or("anonymous"),
+        role(config, user),
+        action,
+        path
+    );
+
+    let mut context = Context::new();
+    context.insert("action", action);
+    context.insert("path", &path);
+    context.insert("paths", &paths);
+    context.insert("note", note.unwrap_or_default());
+    context.insert("user", user.unwrap_or_default());
+
+    Tera::one_off(&config.commit_message_template, &context, false)
+        .map_err(|err| render_error(config, "commit_message_template", err))
+}
+
+#[allow(clippy::result_large_err)]
+fn check_degraded(state: &State) -> Result<(), Response<String>> {
+    match &*state.degraded.read().unwrap() {
+        Some(reason) => Err(response_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("manual intervention required\n\n{}", reason),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Held for the length of a write.
+struct Writing<'state> {
+    _guard: tokio::sync::MutexGuard<'state, ()>,
+    /// Zero if nothing else was writing.
+    waited: Duration,
+}
+
+impl Writing<'_> {
+    /// For the end of a response message, empty if the write didn't have to wait.
+    fn wait_note(&self) -> String {
+        if self.waited.is_zero() {
+            return String::new();
+        }
+        format!("\nwaited {}ms for the build lock", self.waited.as_millis())
+    }
+
+    /// Refuses the write if one queued ahead of it changed the file while it waited. before is
+    /// what was in the file before waiting, None if it didn't exist.
+    async fn check_unchanged(
+        &self,
+        config: &Config,
+        actual_path: &Path,
+        before: &Option<String>,
+    ) -> Result<(), Response<String>> {
+        if self.waited.is_zero() || read_blog_file(config, actual_path).await.ok() == *before {
+            return Ok(());
+        }
+        Err(response_with_status(
+            StatusCode::CONFLICT,
+            format!(
+                "{} was changed by another edit while this one waited {}ms for the build lock, \
+                 reload and try again",
+                actual_path.display(),
+                self.waited.as_millis()
+            ),
+        ))
+    }
+}
+
+/// Holds writes back while max_pending_builds revisions are waiting on a debounced build, since
+/// each edit pushes the build back by another build_debounce_ms.
+async fn wait_for_builds(config: &Config, state: &State) -> Result<Duration, Response<String>> {
+    let Some(max_pending_builds) = config.max_pending_builds else {
+        return Ok(Duration::ZERO);
+    };
+    let full = || state.builds.lock().unwrap().pending >= max_pending_builds;
+    if !full() {
+        return Ok(Duration::ZERO);
+    }
+
+    let busy = || {
+        response_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "{} edits are already waiting to be built, try again",
+                max_pending_builds
+            ),
+        )
+    };
+    if config.reject_when_busy {
+        return Err(busy());
+    }
+
+    let started = Instant::now();
+    let timeout = Duration::from_secs(config.busy_timeout_secs);
+    while full() {
+        if started.elapsed() >= timeout {
+            return Err(busy());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(started.elapsed())
+}
+
+async fn lock_writes<'state>(
+    config: &Config,
+    state: &'state State,
+) -> Result<Writing<'state>, Response<String>> {
+    check_degraded(state)?;
+    let waited_for_builds = wait_for_builds(config, state).await?;
+    if let Ok(guard) = state.writes.try_lock() {
+        return Ok(Writing {
+            _guard: guard,
+            waited: waited_for_builds,
+        });
+    }
+
+    let busy = || {
+        response_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "build in progress, try again",
+        )
+    };
+    if config.reject_when_busy {
+        return Err(busy());
+    }
+
+    let started = Instant::now();
+    state.writes_queued.fetch_add(1, Ordering::Relaxed);
+    let guard = tokio::time::timeout(
+        Duration::from_secs(config.busy_timeout_secs),
+        state.writes.lock(),
+    )
+    .await;
+    state.writes_queued.fetch_sub(1, Ordering::Relaxed);
+    let guard = guard.map_err(|_| busy())?;
+    // the write we were waiting on may have failed to reset
+    check_degraded(state)?;
+    Ok(Writing {
+        _guard: guard,
+        waited: waited_for_builds + started.elapsed(),
+    })
+}
+
+#[derive(Default)]
+struct BuildQueue {
+    queued_at: Option<Instant>,
+    worker: bool,
+    /// Revisions made since the queued build was first queued.
+    pending: usize,
+}
+
+fn queue_build(config: &'static Config, state: &'static State, window: Duration) {
+    let mut builds = state.builds.lock().unwrap();
+    builds.queued_at = Some(Instant::now());
+    builds.pending += 1;
+    if !builds.worker {
+        builds.worker = true;
+        tokio::spawn(debounced_build(config, state, window));
+    }
+}
+
+async fn debounced_build(config: &'static Config, state: &'static State, window: Duration) {
+    loop {
+        let wait = {
+            let mut builds = state.builds.lock().unwrap();
+            match builds.queued_at {
+                Some(queued_at) => (queued_at + window).saturating_duration_since(Instant::now()),
+                None => {
+                    builds.worker = false;
+                    return;
+                }
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+            continue;
+        }
+
+        {
+            let mut builds = state.builds.lock().unwrap();
+            if builds
+                .queued_at
+                .is_some_and(|queued_at| queued_at.elapsed() < window)
+            {
+                continue;
+            }
+            builds.queued_at = None;
+            builds.pending = 0;
+        }
+
+        let writing = state.writes.lock().await;
+        let result = match rebuild(config, state).await {
+            Ok(_) => copy_build(config).await,
+            Err(err) => Err(err),
+        };
+        drop(writing);
+        match result {
+            Ok(_) => println!("debounced build finished"),
+            Err(err) => println!("debounced build failed: {}", err.body()),
+        }
+    }
+}
+
+async fn copy_build(config: &Config) -> Result<String, Response<String>> {
+    command_stdout(
+        config,
+        config.copy_command.iter().map(|s| s.as_str()).chain([
+            format!("{}", config.blog_build_dir.display()).as_str(),
+            format!("{}", config.dest_dir.display()).as_str(),
+        ]),
+    )
+    .await
+}
+
+/// What each step of creating a revision printed.
+#[derive(Serialize, Default)]
+struct RevisionOutput {
+    format: String,
+    build: String,
+    stage: String,
+    commit: String,
+    prune: String,
+    copy: String,
+    sitemap: String,
+    /// head_revision and branch_command right after committing, while writes is still held.
+    #[serde(skip)]
+    revision: Option<String>,
+    #[serde(skip)]
+    branch: Option<String>,
+}
+
+impl Display for RevisionOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (step, output) in [
+            ("format", &self.format),
+            ("build", &self.build),
+            ("sitemap", &self.sitemap),
+            ("stage", &self.stage),
+            ("commit", &self.commit),
+            ("prune", &self.prune),
+            ("copy", &self.copy),
+        ] {
+            if !output.trim().is_empty() {
+                writeln!(f, "== {} ==\n{}", step, output.trim_end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// With a branch, this is run by on_branch with it checked out. The site follows the current
+/// branch, so nothing is built until POST /admin/promote.
+async fn create_revision(
+    config: &'static Config,
+    state: &'static State,
+    actual_paths: &[&Path],
+    message: String,
+    skip_build: bool,
+    branch: Option<&str>,
+) -> Result<RevisionOutput, Response<String>> {
+    let skip_build = skip_build || branch.is_some();
+    let mut paths = actual_paths
+        .iter()
+        .map(|actual_path| format!("{}", actual_path.display()))
+        .collect::<Vec<_>>();
+
+    if !skip_build {
+        touch_after_edit(config).await;
+    }
+
+    let mut revision_output = RevisionOutput::default();
+    if config.sitemap_before_build && !skip_build {
+        revision_output.sitemap = regenerate_sitemap(config).await;
+    }
+    if config.build_debounce_ms.is_none() && !skip_build {
+        revision_output.build = rebuild(config, state).await?;
+    }
+    if !skip_build {
+        if !config.sitemap_before_build {
+            revision_output.sitemap = regenerate_sitemap(config).await;
+        }
+        // committed along with the edit so the tree isn't left dirty
+        if let Some(sitemap_path) = config.sitemap_path.as_ref().filter(|path| path.exists()) {
+            paths.push(format!("{}", sitemap_path.display()));
+        }
+    }
+
+    revision_output.stage = command_stdout(
+        config,
+        config
+            .stage_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain(paths.iter().map(|s| s.as_str())),
+    )
+    .await?;
+
+    revision_output.commit = command_stdout(
+        config,
+        config
+            .create_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain([message.as_str()]),
+    )
+    .await?;
+
+    revision_output.revision =
+        command_stdout(config, config.head_revision.iter().map(|s| s.as_str()))
+            .await
+            .ok()
+            .map(|revision| revision.trim().to_string());
+    if let Some(branch_command) = &config.branch_command {
+        revision_output.branch = command_stdout(config, branch_command.iter().map(|s| s.as_str()))
+            .await
+            .ok()
+            .map(|branch| branch.trim().to_string())
+            .filter(|branch| !branch.is_empty());
+    }
+
+    if config.prune_interval_secs.is_none() {
+        revision_output.prune = prune_revisions(config).await;
+    }
+
+    if let Some(branch) = branch {
+        revision_output.build = format!(
+            "committed to {}, the site won't change until it's promoted",
+            branch
+        );
+        return Ok(revision_output);
+    }
+
+    if skip_build {
+        revision_output.build =
+            String::from("build skipped, the site won't change until the next build");
+        return Ok(revision_output);
+    }
+
+    match config.build_debounce_ms {
+        Some(build_debounce_ms) => {
+            queue_build(config, state, Duration::from_millis(build_debounce_ms));
+            revision_output.build = format!(
+                "build queued, edits within {}ms of each other share one build",
+                build_debounce_ms
+            );
+        }
+        None => revision_output.copy = copy_build(config).await?,
+    }
+
+    Ok(revision_output)
+}
+
+/// Checks out branch with checkout_command, returning the branch that was checked out before
+/// according to branch_command.
+/// Runs write with branch checked out, so it writes over that branch's version of the file, and
+/// checks the current branch out again afterwards. write should reset if it fails, or the tree
+/// may be too dirty to switch back.
+async fn on_branch<T>(
+    config: &Config,
+    state: &State,
+    branch: Option<&str>,
+    write: impl Future<Output = Result<T, Response<String>>>,
+) -> Result<T, Response<String>> {
+    let Some(branch) = branch else {
+        return write.await;
+    };
+
+    let previous_branch = checkout_branch(config, branch).await?;
+    let written = write.await;
+    if let Err(mut err) = checkout_branch(config, &previous_branch).await {
+        // later edits would quietly land on the other branch
+        println!(
+            "couldn't check out {} again, refusing writes until cleared",
+            previous_branch
+        );
+        *state.degraded.write().unwrap() = Some(format!(
+            "{}: left on branch {} instead of {}\n\n{}",
+            Utc::now().to_rfc3339(),
+            branch,
+            previous_branch,
+            err.body()
+        ));
+        err.body_mut().push_str(&format!(
+            "\n\ncouldn't check out {} again, writes are refused until an admin clears it",
+            previous_branch
+        ));
+        return Err(err);
+    }
+    written
+}
+
+async fn checkout_branch(config: &Config, branch: &str) -> Result<String, Response<String>> {
+    let (Some(branch_command), Some(checkout_command)) =
+        (&config.branch_command, &config.checkout_command)
+    else {
+        return Err(five_hundred(
+            "branch_command and checkout_command aren't set",
+        ));
+    };
+
+    let current = command_stdout(config, branch_command.iter().map(|s| s.as_str())).await?;
+    let current = current.trim();
+    if current.is_empty() {
+        return Err(five_hundred(format!(
+            "{} isn't on a branch, not switching to {}",
+            config.blog_dir.display(),
+            branch
+        )));
+    }
+
+    command_stdout(
+        config,
+        checkout_command.iter().map(|s| s.as_str()).chain([branch]),
+    )
+    .await?;
+    Ok(current.to_string())
+}
+
+/// Bumps the mtime of touch_after_edit, for generators that only rebuild pages whose source
+/// changed. A missing file is only warned about.
+async fn touch_after_edit(config: &Config) {
+    for path in &config.touch_after_edit {
+        let to_touch = path.clone();
+        let touched = tokio::task::spawn_blocking(move || {
+            std::fs::File::open(to_touch)?.set_modified(std::time::SystemTime::now())
+        })
+        .await;
+        if let Ok(Err(err)) = touched {
+            println!("WARNING: couldn't touch {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Runs sitemap_command, if there is one. Failures are only warned about, the edit still goes
+/// through.
+async fn regenerate_sitemap(config: &Config) -> String {
+    let Some(sitemap_command) = &config.sitemap_command else {
+        return String::new();
+    };
+    match command_stdout(config, sitemap_command.iter().map(|s| s.as_str())).await {
+        Ok(output) => output,
+        Err(err) => {
+            println!("WARNING: sitemap_command failed: {}", err.body());
+            format!("WARNING: sitemap_command failed: {}", err.body())
+        }
+    }
+}
+
+async fn count_revisions(config: &Config) -> Result<usize, Response<String>> {
+    command_stdout(config, config.count_revisions.iter().map(|s| s.as_str()))
+        .await?
+        .trim()
+        .parse()
+        .map_err(five_hundred)
+}
+
+/// Runs prune_command with max_revisions appended once count_revisions goes over it. This
+/// rewrites history, so it's only done when both are configured, and callers must hold writes.
+/// Failures are only logged, the revision that pushed the count over has already been made.
+async fn prune_revisions(config: &Config) -> String {
+    let (Some(max_revisions), Some(prune_command)) = (config.max_revisions, &config.prune_command)
+    else {
+        return String::new();
+    };
+
+    let result = match count_revisions(config).await {
+        Ok(count) if count <= max_revisions => return String::new(),
+        Ok(count) => {
+            println!(
+                "{} revisions is over max_revisions ({}), pruning",
+                count, max_revisions
+            );
+            let max_revisions = max_revisions.to_string();
+            command_stdout(
+                config,
+                prune_command
+                    .iter()
+                    .map(|s| s.as_str())
+                    .chain([max_revisions.as_str()]),
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(output) => output,
+        Err(err) => {
+            println!("WARNING: pruning revisions failed: {}", err.body());
+            format!("pruning failed: {}", err.body())
+        }
+    }
+}
+
+/// Queues a build when something other than this editor changes blog_dir, like a `git pull` on the
+/// server. Changes made while writes is held are taken to be our own, since whatever holds it will
+/// build anyway, and the build output and .git are ignored so building doesn't trigger itself.
+fn watch_blog_dir(
+    config: &'static Config,
+    state: &'static State,
+) -> notify::Result<RecommendedWatcher> {
+    let ignored = [
+        Some(config.blog_build_dir.clone()),
+        Some(config.dest_dir.clone()),
+        Some(config.blog_dir.join(".git")),
+        config.backup_dir.clone(),
+        config.drafts_dir.clone(),
+    ];
+
+    let (changed, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                println!("WARNING: watching {}: {}", config.blog_dir.display(), err);
+                return;
+            }
+        };
+        if event.kind.is_access() || event.kind.is_other() || state.writes.try_lock().is_err() {
+            return;
+        }
+        if let Some(path) = event.paths.iter().find(|path| {
+            !ignored
+                .iter()
+                .flatten()
+                .any(|ignored| path.starts_with(ignored))
+        }) {
+            let _ = changed.send(path.clone());
+        }
+    })?;
+    watcher.watch(&config.blog_dir, RecursiveMode::Recursive)?;
+
+    tokio::spawn(async move {
+        while let Some(path) = changes.recv().await {
+            println!(
+                "{} changed outside the editor, queueing a build",
+                path.display()
+            );
+            queue_build(
+                config,
+                state,
+                Duration::from_millis(config.watch_debounce_ms),
+            );
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Prunes every prune_interval_secs instead of after each commit.
+async fn prune_periodically(config: &'static Config, state: &State, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if check_degraded(state).is_err() {
+            continue;
+        }
+        let writing = state.writes.lock().await;
+        prune_revisions(config).await;
+        drop(writing);
+    }
+}
+
+#[derive(Serialize)]
+struct Webhook {
+    action: String,
+    path: String,
+    commit: Option<String>,
+    branch: Option<String>,
+    note: Option<String>,
+    user: Option<String>,
+    timestamp: String,
+}
+
+/// Tell webhook_url about a new revision in the background. Failures are only logged, the revision
+/// has already been made.
+fn notify_webhook(
+    config: &'static Config,
+    client: &reqwest::Client,
+    action: &str,
+    actual_path: &Path,
+    note: Option<&str>,
+    user: Option<&str>,
+    output: &RevisionOutput,
+) {
+    let Some(webhook_url) = &config.webhook_url else {
+        return;
+    };
+    if !config
+        .webhook_actions
+        .iter()
+        .any(|allowed| allowed == action)
+    {
+        return;
+    }
+
+    let webhook = Webhook {
+        action: action.into(),
+        path: actual_path
+            .strip_prefix(&config.blog_dir)
+            .unwrap_or(actual_path)
+            .display()
+            .to_string(),
+        commit: output.revision.clone(),
+        branch: output.branch.clone(),
+        note: note.filter(|note| !note.is_empty()).map(String::from),
+        user: user.map(String::from),
+        timestamp: Utc::now().to_rfc3339(),
+    };
+    let client = client.clone();
+
+    tokio::spawn(async move {
+        let result = client
+            .post(webhook_url.clone())
+            .timeout(Duration::from_secs(config.webhook_timeout_secs))
+            .json(&webhook)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            println!(
+                "WARNING: webhook for {} {} failed: {}",
+                webhook.action, webhook.path, err
+            );
+        }
+    });
+}
+
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+/// Plain text by default, or the message and each step's output as separate fields if the client
+/// asked for JSON.
+#[allow(clippy::result_large_err)]
+fn revision_response(
+    headers: &HeaderMap,
+    message: String,
+    page_url: Option<Url>,
+    output: &RevisionOutput,
+) -> Result<Response<String>, Response<String>> {
+    #[derive(Serialize)]
+    struct RevisionResponse<'a> {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        page_url: Option<Url>,
+        #[serde(flatten)]
+        output: &'a RevisionOutput,
+    }
+
+    if wants_json(headers) {
+        let body = serde_json::to_string(&RevisionResponse {
+            message,
+            page_url,
+            output,
+        })
+        .map_err(five_hundred)?;
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap());
+    }
+
+    let message = match page_url {
+        Some(page_url) => format!("{}\nview published page: {}", message, page_url),
+        None => message,
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{}\n\n{}", message, output))
+        .unwrap())
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut components = path.components().peekable();
+    let mut ret = if let Some(c @ Component::Prefix(..)) = components.peek().cloned() {
+        components.next();
+        PathBuf::from(c.as_os_str())
+    } else {
+        PathBuf::new()
+    };
+
+    for component in components {
+        match component {
+            Component::Prefix(..) => unreachable!(),
+            Component::RootDir => {
+                ret.push(component.as_os_str());
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                ret.pop();
+            }
+            Component::Normal(c) => {
+                ret.push(c);
+            }
+        }
+    }
+    ret
+}
+
+#[derive(Serialize)]
+struct Revision {
+    hash: String,
+    date: String,
+    author: String,
+    subject: String,
+    /// Only filled in for the revert page.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files: Vec<String>,
+}
+
+fn parse_revisions(output: &str) -> Vec<Revision> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            Some(Revision {
+                hash: fields.next()?.into(),
+                date: fields.next()?.into(),
+                author: fields.next()?.into(),
+                subject: fields.next().unwrap_or_default().into(),
+                files: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+async fn get_feed(
+    config: &Config,
+    tera: &RwLock<Tera>,
+) -> Result<Response<String>, Response<String>> {
+    let revisions_output = command_stdout(
+        config,
+        config.structured_revisions.iter().map(|s| s.as_str()),
+    )
+    .await?;
+    let mut revisions = parse_revisions(&revisions_output);
+    revisions.truncate(config.feed_entries);
+
+    let mut context = base_context(config);
+    context.insert("url", &mounted_url(config, &config.url));
+    context.insert(
+        "updated",
+        &revisions
+            .first()
+            .map(|revision| revision.date.clone())
+            .unwrap_or_else(|| Utc::now().to_rfc3339()),
+    );
+    context.insert("revisions", &revisions);
+    let feed = render_template(tera, "feed.xml", &context)
+        .map_err(|err| render_error(config, "feed.xml", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/atom+xml")
+        .body(feed)
+        .unwrap())
+}
+
+async fn walk_files(config: &Config, dir: &Path) -> Result<Vec<PathBuf>, Response<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await.map_err(five_hundred)?;
+        while let Some(entry) = entries.next_entry().await.map_err(five_hundred)? {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.')
+                || path.starts_with(&config.blog_build_dir)
+            {
+                continue;
+            }
+
+            if entry.file_type().await.map_err(five_hundred)?.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    path: String,
+    matches: usize,
+    snippets: Vec<(usize, String)>,
+}
+
+async fn get_search(
+    config: &Config,
+    tera: &RwLock<Tera>,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let q = query.get("q").map(|q| q.as_str()).unwrap_or_default();
+    let pattern = if query.get("regex").map(|s| s.as_str()) == Some("on") {
+        Regex::new(q)
+    } else {
+        Regex::new(&format!("(?i){}", regex::escape(q)))
+    }
+    .map_err(four_hundred)?;
+
+    let mut results = Vec::new();
+    if !q.is_empty() {
+        for path in walk_files(config, &config.blog_dir).await? {
+            // nothing /edit wouldn't show
+            if check_editable(config, &path).is_err() || check_protected(config, &path).is_err() {
+                continue;
+            }
+            let Ok(content) = read_blog_file(config, &path).await else {
+                continue;
+            };
+            if content.chars().take(8000).any(|c| c == '\0') {
+                continue;
+            }
+
+            let mut matches = 0;
+            let mut snippets = Vec::new();
+            for (number, line) in content.lines().enumerate() {
+                let count = pattern.find_iter(line).count();
+                if count == 0 {
+                    continue;
+                }
+                matches += count;
+                if snippets.len() < 3 {
+                    snippets.push((
+                        number + 1,
+                        line.trim()
+                            .chars()
+                            .take(config.search_snippet_chars)
+                            .collect(),
+                    ));
+                }
+            }
+
+            if matches > 0 {
+                results.push(SearchResult {
+                    path: path
+                        .strip_prefix(&config.blog_dir)
+                        .unwrap()
+                        .display()
+                        .to_string(),
+                    matches,
+                    snippets,
+                });
+            }
+        }
+    }
+
+    results.sort_by_key(|result| std::cmp::Reverse(result.matches));
+    let truncated = results.len() > config.search_max_results;
+    results.truncate(config.search_max_results);
+
+    let mut context = base_context(config);
+    context.insert("q", q);
+    context.insert("results", &results);
+    context.insert("truncated", &truncated);
+    let page = render_template(tera, "search.html", &context)
+        .map_err(|err| render_error(config, "search.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+async fn revision_files(
+    config: &Config,
+    state: &State,
+    revision: &str,
+) -> Result<Vec<String>, Response<String>> {
+    let Some(revision_files_command) = &config.revision_files_command else {
+        return Ok(Vec::new());
+    };
+
+    if let Some(files) = state.revision_files.lock().unwrap().get(revision) {
+        return Ok(files.clone());
+    }
+
+    let files = command_stdout(
+        config,
+        revision_files_command
+            .iter()
+            .map(|s| s.as_str())
+            .chain([revision]),
+    )
+    .await?
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .map(String::from)
+    .collect::<Vec<_>>();
+
+    // history doesn't change under a hash, so this never needs invalidating
+    state
+        .revision_files
+        .lock()
+        .unwrap()
+        .insert(revision.into(), files.clone());
+    Ok(files)
+}
+
+async fn get_revert(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+) -> Result<Response<String>, Response<String>> {
+    let revisions_output = command_stdout(
+        config,
+        config.structured_revisions.iter().map(|s| s.as_str()),
+    )
+    .await?;
+
+    let mut revisions = parse_revisions(&revisions_output);
+    for revision in revisions.iter_mut().take(config.revision_files_limit) {
+        if let Ok(hash) = revision_hash(&revision.hash) {
+            revision.files = revision_files(config, state, hash).await?;
+        }
+    }
+
+    let mut context = base_context(config);
+    context.insert("revisions", &revisions);
+    let page = render_template(tera, "revert.html", &context)
+        .map_err(|err| render_error(config, "revert.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+/// Hint for the client-side editor about what kind of file it's editing.
+fn editor_mode(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("md" | "markdown") => "markdown",
+        Some("html" | "htm") => "html",
+        _ => "text",
+    }
+}
+
+/// Prefixed to base64 content in the editor, so a binary file isn't mistaken for text.
+const BINARY_MARKER: &str = "base64:";
+
+/// Binary files aren't found through the blog, the edit path is taken relative to blog_dir.
+#[allow(clippy::result_large_err)]
+fn binary_file(config: &Config, path: &str) -> Result<PathBuf, Response<String>> {
+    let actual_path = contained_path(&config.blog_dir, path.trim_start_matches('/'))?
+        .canonicalize()
+        .map_err(|_| {
+            response_with_status(StatusCode::NOT_FOUND, format!("no such file {}", path))
+        })?;
+    if !actual_path.starts_with(&config.blog_dir) {
+        return Err(outside_allowed(None, &actual_path, &config.blog_dir));
+    }
+
+    let extension = actual_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !config.binary_extensions.contains(&extension) {
+        return Err(four_hundred(format!(
+            "can't edit .{} files as binary, allowed extensions are {}",
+            extension,
+            config.binary_extensions.join(", ")
+        )));
+    }
+    check_editable_prefix(config, &actual_path)?;
+    check_protected(config, &actual_path)?;
+    Ok(actual_path)
+}
+
+async fn get_edit_binary(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    path: FullPath,
+    session: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let path_str = path
+        .as_str()
+        .strip_prefix(config.mount_prefix.as_str())
+        .and_then(|path| path.strip_prefix("/edit"))
+        .unwrap();
+    let actual_path = binary_file(config, path_str)?;
+
+    let (session, new_session) = match session {
+        Some(session) if !session.is_empty() => (session, false),
+        _ => (state.new_session(), true),
+    };
+    let locked_by_other = !state.lock_path(config, &actual_path, &session);
+
+    let bytes = tokio::fs::read(&actual_path).await.map_err(five_hundred)?;
+    let content = format!("{}{}", BINARY_MARKER, BASE64.encode(&bytes));
+
+    let mut context = base_context(config);
+    context.insert("content", &content);
+    context.insert(
+        "file",
+        &actual_path
+            .strip_prefix(&config.blog_dir)
+            .unwrap()
+            .display()
+            .to_string(),
+    );
+    context.insert("front_matter", "");
+    context.insert("body", &content);
+    context.insert("binary", &true);
+    context.insert("mode", "text");
+    context.insert("stats", &content_stats(config, ""));
+    context.insert("words_per_minute", &config.words_per_minute);
+    context.insert("locked_by_other", &locked_by_other);
+    context.insert("new_file", &false);
+    context.insert("lock_ttl_minutes", &config.edit_lock_ttl_secs.div_ceil(60));
+    context.insert("draft", &false);
+    // drafts, autosaves and previews are text only
+    context.insert("drafts", &false);
+    context.insert("previews", &false);
+    context.insert("autosave_interval_secs", &config.autosave_interval_secs);
+    let page = render_template(tera, "edit.html", &context)
+        .map_err(|err| render_error(config, "edit.html", err))?;
+
+    let mut response = Response::builder()
+        .header("Content-Type", "text/html")
+        .header(CACHE_CONTROL, "no-cache");
+    if new_session {
+        response = response.header(
+            SET_COOKIE,
+            format!(
+                "editor_session={}; Path=/; HttpOnly; SameSite=Strict",
+                session
+            ),
+        );
+    }
+    Ok(response.body(page).unwrap())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write_binary(
+    config: &'static Config,
+    state: &'static State,
+    actual_path: &Path,
+    bytes: &[u8],
+    note: Option<&str>,
+    user: Option<&str>,
+    skip_build: bool,
+    branch: Option<&str>,
+) -> Result<RevisionOutput, Response<String>> {
+    on_branch(
+        config,
+        state,
+        branch,
+        reset_if_err(config, state, async {
+            tokio::fs::write(actual_path, bytes)
+                .await
+                .map_err(five_hundred)?;
+            let message = commit_message(config, "edit", &[actual_path], note, user)?;
+            create_revision(config, state, &[actual_path], message, skip_build, branch).await
+        }),
+    )
+    .await
+}
+
+/// Writes base64 content from the editor as-is, none of the text normalization applies.
+async fn post_edit_binary(
+    config: &'static Config,
+    state: &'static State,
+    path_str: &str,
+    form: &HashMap<String, String>,
+    session: Option<&str>,
+    user: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let actual_path = binary_file(config, path_str)?;
+    let content = match (form.get("body"), form.get("content")) {
+        (Some(content), _) | (None, Some(content)) => content,
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+    let Some(encoded) = content.trim().strip_prefix(BINARY_MARKER) else {
+        return Err(four_hundred(format!(
+            "binary content must start with {}",
+            BINARY_MARKER
+        )));
+    };
+    let bytes = BASE64
+        .decode(encoded.split_whitespace().collect::<String>())
+        .map_err(|err| four_hundred(format!("invalid base64: {}", err)))?;
+    check_content_bytes(config, bytes.len())?;
+    let note = form.get("note").map(|s| s.as_str());
+    check_note(config, note)?;
+
+    let skip_build = skip_build(config, form)?;
+    let branch = branch(config, form)?;
+
+    let writing = lock_writes(config, state).await?;
+    let output = write_binary(
+        config,
+        state,
+        &actual_path,
+        &bytes,
+        note,
+        user,
+        skip_build,
+        branch,
+    )
+    .await?;
+    state.unlock_path(&actual_path, session);
+    METRICS.edits.inc();
+    notify_webhook(
+        config,
+        &state.client,
+        "edit",
+        &actual_path,
+        note,
+        user,
+        &output,
+    );
+
+    revision_response(
+        headers,
+        format!(
+            "wrote {} bytes to {}{}",
+            bytes.len(),
+            actual_path.display(),
+            writing.wait_note()
+        ),
+        page_url(config, &actual_path),
+        &output,
+    )
+}
+
+async fn get_edit(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    path: FullPath,
+    headers: HeaderMap,
+    session: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let path_str = path
+        .as_str()
+        .strip_prefix(config.mount_prefix.as_str())
+        .and_then(|path| path.strip_prefix("/edit"))
+        .unwrap();
+    let actual_path = path_to_new_or_existing_file(config, state, &forwarded, path_str).await?;
+
+    let (session, new_session) = match session {
+        Some(session) if !session.is_empty() => (session, false),
+        _ => (state.new_session(), true),
+    };
+    let locked_by_other = !state.lock_path(config, &actual_path, &session);
+
+    let draft = newer_draft(config, &actual_path).await;
+    let source = draft.as_ref().unwrap_or(&actual_path);
+    let autosave = match autosave_path(config, &actual_path) {
+        Ok(autosave_path) => match newer_than(autosave_path, source).await {
+            Some(autosave_path) => tokio::fs::read_to_string(autosave_path).await.ok(),
+            None => None,
+        },
+        Err(_) => None,
+    };
+    // the file can disappear between resolving the path and reading it, saving recreates it
+    let (page_content, new_file) = match read_blog_file(config, source).await {
+        Ok(content) => (content, false),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (String::new(), true),
+        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => return Err(five_hundred(err)),
+        Err(_) => return Err(five_hundred(format!("couldn't read {}", source.display()))),
+    };
+
+    // the page shows more than the file, a 304 mustn't hide a new lock banner or autosave
+    let etag = format!(
+        "\"{}\"",
+        content_hash(
+            format!(
+                "{}\0{}\0{}\0{}\0{}",
+                page_content,
+                locked_by_other,
+                draft.is_some(),
+                new_file,
+                autosave.as_deref().unwrap_or_default()
+            )
+            .as_bytes()
+        )
+    );
+    let last_modified = tokio::fs::metadata(source)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(DateTime::<Utc>::from);
+
+    let not_modified = match (
+        headers
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok()),
+        headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok()),
+    ) {
+        (Some(if_none_match), _) => if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*"),
+        (None, Some(if_modified_since)) if !locked_by_other && autosave.is_none() => last_modified
+            .is_some_and(|last_modified| {
+                last_modified.timestamp() <= if_modified_since.timestamp()
+            }),
+        (None, _) => false,
+    };
+
+    let mut response = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, "no-cache");
+    if new_session {
+        response = response.header(
+            SET_COOKIE,
+            format!(
+                "editor_session={}; Path=/; HttpOnly; SameSite=Strict",
+                session
+            ),
+        );
+    }
+    if let Some(last_modified) = last_modified {
+        response = response.header(
+            LAST_MODIFIED,
+            last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        );
+    }
+
+    if not_modified {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(String::new())
+            .unwrap());
+    }
+
+    let (front_matter, body) = split_front_matter(&page_content);
+
+    let mut context = base_context(config);
+    context.insert("content", &page_content);
+    context.insert(
+        "file",
+        &actual_path
+            .strip_prefix(&config.blog_dir)
+            .unwrap_or(&actual_path)
+            .display()
+            .to_string(),
+    );
+    context.insert("front_matter", front_matter);
+    // autoescaped, so content like </textarea> can't end the textarea early
+    context.insert("body", body);
+    context.insert("mode", editor_mode(&actual_path));
+    context.insert("stats", &content_stats(config, &page_content));
+    context.insert("words_per_minute", &config.words_per_minute);
+    context.insert("locked_by_other", &locked_by_other);
+    context.insert("new_file", &new_file);
+    context.insert("lock_ttl_minutes", &config.edit_lock_ttl_secs.div_ceil(60));
+    context.insert("draft", &draft.is_some());
+    context.insert("drafts", &config.drafts_dir.is_some());
+    context.insert("previews", &config.preview_dir.is_some());
+    context.insert("autosave_interval_secs", &config.autosave_interval_secs);
+    if let Some(autosave) = &autosave {
+        let (front_matter, body) = split_front_matter(autosave);
+        context.insert("autosave_front_matter", front_matter);
+        context.insert("autosave_body", body);
+    }
+
+    let page = match render_template(tera, "edit.html", &context) {
+        Ok(page) => page,
+        Err(err) => return Err(render_error(config, "edit.html", err)),
+    };
+
+    let response = response
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap();
+
+    Ok(response)
+}
+
+fn slugify(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| if c.is_whitespace() { '-' } else { c })
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')
+        .collect::<String>()
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[allow(clippy::result_large_err)]
+fn slugify_filename(filename: &str) -> Result<String, Response<String>> {
+    let path = Path::new(filename);
+    let stem = slugify(&path.file_stem().unwrap_or_default().to_string_lossy());
+    if stem.is_empty() {
+        return Err(four_hundred(format!(
+            "can't make a filename out of {}",
+            filename
+        )));
+    }
+
+    let mut slugged = path.with_file_name(stem);
+    if let Some(extension) = path.extension() {
+        slugged.set_extension(extension.to_string_lossy().to_lowercase());
+    }
+    Ok(slugged.to_string_lossy().into_owned())
+}
+
+async fn post_template(
+    config: &Config,
+    tera: &RwLock<Tera>,
+    name: &str,
+    query: &HashMap<String, String>,
+) -> Result<String, Response<String>> {
+    let Some(post_templates_dir) = &config.post_templates_dir else {
+        return Err(four_hundred("post templates are not configured"));
+    };
+
+    let template_path = contained_path(post_templates_dir, name)?;
+    let Ok(template) = tokio::fs::read_to_string(&template_path).await else {
+        return Err(response_with_status(
+            StatusCode::NOT_FOUND,
+            format!("no post template named {}", name),
+        ));
+    };
+
+    let title = query.get("title").map(|s| s.as_str()).unwrap_or_default();
+    let now = Utc::now();
+    let mut context = base_context(config);
+    context.insert("title", title);
+    context.insert("slug", &slugify(title));
+    context.insert("date", &now.format("%Y-%m-%d").to_string());
+    context.insert("now", &now.to_rfc3339());
+    let content = Tera::one_off(&template, &context, false)
+        .map_err(|err| render_error(config, &format!("post template {}", name), err))?;
+
+    let mut context = base_context(config);
+    context.insert("content", &content);
+    render_template(tera, "publish.html", &context)
+        .map_err(|err| render_error(config, "publish.html", err))
+}
+
+async fn get_publish(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    if let Some(name) = query.get("template") {
+        return Ok(Response::builder()
+            .header("Content-Type", "text/html")
+            .body(post_template(config, tera, name, &query).await?)
+            .unwrap());
+    }
+
+    let cached_page = state.publish_page.lock().unwrap().clone();
+    let page = match cached_page {
+        Some(page) => page,
+        None => match render_template(tera, "publish.html", &base_context(config)) {
+            Ok(page) => {
+                *state.publish_page.lock().unwrap() = Some(page.clone());
+                page
+            }
+            Err(err) => return Ok(render_error(config, "publish.html", err)),
+        },
+    };
+
+    let response = Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap();
+
+    Ok(response)
+}
+
+#[allow(clippy::result_large_err)]
+fn revision_hash(revision_name: &str) -> Result<&str, Response<String>> {
+    let Some(revision) = revision_name.split_whitespace().next() else {
+        return Err(four_hundred(format!(
+            "no hash in revision {}",
+            revision_name
+        )));
+    };
+
+    if !revision.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(four_hundred(format!("invalid revision {}", revision)));
+    }
+
+    Ok(revision)
+}
+
+async fn get_revert_diff(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let Some(revision_name) = query.get("revision") else {
+        return Err(four_hundred("no revision to preview?"));
+    };
+    let revision = revision_hash(revision_name)?;
+
+    let diff = command_stdout(
+        config,
+        config
+            .show_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain([revision]),
+    )
+    .await?;
+
+    let mut context = base_context(config);
+    context.insert("revision", revision_name);
+    context.insert("diff", &diff);
+    context.insert("token", &state.revert_token(config, revision));
+    let page = render_template(tera, "revert_diff.html", &context)
+        .map_err(|err| render_error(config, "revert_diff.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+/// Line diff of old against new, each line prefixed with ' ', '-' or '+'. The diff gives up
+/// looking for a minimal result after a second, so a huge page can't hold up the request.
+fn line_diff(old: &str, new: &str) -> String {
+    let mut diff = String::new();
+    for change in TextDiff::configure()
+        .timeout(Duration::from_secs(1))
+        .diff_lines(old, new)
+        .iter_all_changes()
+    {
+        diff.push(match change.tag() {
+            ChangeTag::Equal => ' ',
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+        });
+        let line = change.value();
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        diff.push_str(line.strip_suffix('\r').unwrap_or(line));
+        diff.push('\n');
+    }
+    diff
+}
+
+async fn get_edit_diff_rev(
+    config: &Config,
+    tera: &RwLock<Tera>,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let Some(path) = query.get("path") else {
+        return Err(four_hundred("missing path"));
+    };
+    let Some(revision_name) = query.get("revision") else {
+        return Err(four_hundred("no revision to diff against?"));
+    };
+    let revision = revision_hash(revision_name)?;
+
+    let actual_path = blog_file(config, path)?;
+    let relative_path = actual_path.strip_prefix(&config.blog_dir).unwrap();
+    let current = read_blog_file(config, &actual_path)
+        .await
+        .map_err(five_hundred)?;
+    let old = command_stdout(
+        config,
+        config
+            .show_file_at_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain([format!("{}:{}", revision, relative_path.display()).as_str()]),
+    )
+    .await?;
+
+    let mut context = base_context(config);
+    context.insert("path", &relative_path.display().to_string());
+    context.insert("revision", revision);
+    context.insert("diff", &line_diff(&old, &current));
+    let page = render_template(tera, "diff_rev.html", &context)
+        .map_err(|err| render_error(config, "diff_rev.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+/// Puts one file back the way it was at a revision, as a new edit rather than a revert.
+async fn post_restore(
+    config: &'static Config,
+    state: &'static State,
+    form: HashMap<String, String>,
+    session: Option<String>,
+    headers: HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(
+        config,
+        headers
+            .get(AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default()),
+    )?;
+    require_role(config, user.as_deref(), Role::Editor, "edit")?;
+
+    let Some(path) = form.get("path") else {
+        return Err(four_hundred("missing path"));
+    };
+    let Some(revision_name) = form.get("revision") else {
+        return Err(four_hundred("no revision from form?"));
+    };
+    let revision = revision_hash(revision_name)?;
+
+    let actual_path = contained_path(&config.blog_dir, path.trim_start_matches('/'))?;
+    // it may have been deleted since, existing ones might be symlinks out of blog_dir
+    if let Ok(canonical_path) = actual_path.canonicalize() {
+        if !canonical_path.starts_with(&config.blog_dir) {
+            return Err(outside_allowed(None, &canonical_path, &config.blog_dir));
+        }
+    }
+    check_editable(config, &actual_path)?;
+    check_protected(config, &actual_path)?;
+    let relative_path = actual_path
+        .strip_prefix(&config.blog_dir)
+        .unwrap()
+        .display()
+        .to_string();
+
+    let content = file_at_revision(config, &relative_path, revision).await?;
+    let note = match form.get("note").map(|s| s.trim()) {
+        Some(note) if !note.is_empty() => note.to_string(),
+        _ => format!("restored from {}", revision),
+    };
+
+    let writing = lock_writes(config, state).await?;
+    let (content, output) = reset_if_err(
+        config,
+        state,
+        set_content_with_revision(
+            config,
+            state,
+            &actual_path,
+            &content,
+            Some(&note),
+            user.as_deref(),
+            false,
+            None,
+        ),
+    )
+    .await?;
+    edited(
+        config,
+        state,
+        &actual_path,
+        session.as_deref(),
+        Some(&note),
+        user.as_deref(),
+        &output,
+    )
+    .await;
+
+    revision_response(
+        &headers,
+        format!(
+            "restored {} from {} ({}){}",
+            actual_path.display(),
+            revision,
+            content_stats(config, &content),
+            writing.wait_note()
+        ),
+        page_url(config, &actual_path),
+        &output,
+    )
+}
+
+async fn post_revert(
+    config: &Config,
+    state: &State,
+    form: HashMap<String, String>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(config, authorization.as_deref())?;
+    require_role(config, user.as_deref(), Role::Publisher, "revert")?;
+
+    let Some(revision_name) = form.get("revision") else {
+        return Err(four_hundred("no revision from form?"));
+    };
+
+    let revision = revision_hash(revision_name)?;
+
+    let Some(token) = form.get("token") else {
+        return Err(four_hundred(
+            "missing revert token, preview the revision first",
+        ));
+    };
+    // checked under the write lock so two reverts can't both get past it with one token
+    let _writing = lock_writes(config, state).await?;
+    let expires = state
+        .check_revert_token(revision, token)
+        .map_err(four_hundred)?;
+
+    let do_revert_output = command_stdout(
+        config,
+        config
+            .revert_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain([revision]),
+    )
+    .await?;
+    state.use_revert_token(token, expires);
+
+    let prune_output = match config.prune_interval_secs {
+        None => prune_revisions(config).await,
+        Some(_) => String::new(),
+    };
+
+    METRICS.reverts.inc();
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(do_revert_output + &prune_output)
+        .unwrap())
+}
+
+/// Once a page's revision is in: nothing should resolve to it from a stale cache or keep it
+/// locked, its draft is out of date, and the webhook hears about it.
+async fn edited(
+    config: &'static Config,
+    state: &State,
+    actual_path: &Path,
+    session: Option<&str>,
+    note: Option<&str>,
+    user: Option<&str>,
+    output: &RevisionOutput,
+) {
+    state.invalidate_path(actual_path);
+    state.unlock_path(actual_path, session);
+    remove_draft(config, actual_path).await;
+    METRICS.edits.inc();
+    notify_webhook(
+        config,
+        &state.client,
+        "edit",
+        actual_path,
+        note,
+        user,
+        output,
+    );
+}
+
+async fn post_edit(
+    config: &'static Config,
+    state: &'static State,
+    path: FullPath,
+    form: HashMap<String, String>,
+    session: Option<String>,
+    forwarded: Forwarded,
+    headers: HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(
+        config,
+        headers
+            .get(AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default()),
+    )?;
+    let path_str = path
+        .as_str()
+        .strip_prefix(config.mount_prefix.as_str())
+        .and_then(|path| path.strip_prefix("/edit"))
+        .unwrap();
+    if form.get("delete").map(|s| s.as_str()) == Some("on") {
+        require_role(config, user.as_deref(), Role::Publisher, "delete")?;
+    } else {
+        require_role(config, user.as_deref(), Role::Editor, "edit")?;
+    }
+    if form.get("binary").map(|s| s.as_str()) == Some("on") {
+        return post_edit_binary(
+            config,
+            state,
+            path_str,
+            &form,
+            session.as_deref(),
+            user.as_deref(),
+            &headers,
+        )
+        .await;
+    }
+    let actual_path = path_to_new_or_existing_file(config, state, &forwarded, path_str).await?;
+    check_protected(config, &actual_path)?;
+
+    let content = match (form.get("body"), form.get("content")) {
+        _ if form.get("publish_draft").map(|s| s.as_str()) == Some("on") => {
+            load_draft(config, &actual_path).await?
+        }
+        (Some(body), _) => join_front_matter(
+            form.get("front_matter")
+                .map(|s| s.as_str())
+                .unwrap_or_default(),
+            body,
+        ),
+        (None, Some(content)) => content.clone(),
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+    validate_front_matter(split_front_matter(&content).0).map_err(four_hundred)?;
+
+    if form.get("draft").map(|s| s.as_str()) == Some("on") {
+        let draft_path = save_draft(config, &actual_path, &content).await?;
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!(
+                "saved draft of {} to {}",
+                actual_path.display(),
+                draft_path.display()
+            ))
+            .unwrap());
+    }
+
+    check_note(config, form.get("note").map(|s| s.as_str()))?;
+    let skip_build = skip_build(config, &form)?;
+    let branch = branch(config, &form)?;
+    let before = read_blog_file(config, &actual_path).await.ok();
+    if form.get("delete").map(|s| s.as_str()) == Some("on") {
+        let writing = lock_writes(config, state).await?;
+        writing
+            .check_unchanged(config, &actual_path, &before)
+            .await?;
+        let message = commit_message(
+            config,
+            "delete",
+            &[&actual_path],
+            form.get("note").map(|s| s.as_str()),
+            user.as_deref(),
+        )?;
+        let deleted = async {
+            backup(config, &actual_path).await?;
+            match tokio::fs::remove_file(&actual_path).await {
+                Ok(_) => {}
+                Err(err) => return Err(five_hundred(err)),
+            };
+            reset_if_err(
+                config,
+                state,
+                create_revision(config, state, &[&actual_path], message, skip_build, branch),
+            )
+            .await
+        };
+
+        let create_revision_output = match on_branch(config, state, branch, deleted).await {
+            Ok(output) => output,
+            Err(mut err) => {
+                if !tokio::fs::try_exists(&actual_path).await.unwrap_or(false) {
+                    println!(
+                        "reset did NOT restore deleted file {}",
+                        actual_path.display()
+                    );
+                    err.body_mut().push_str(&format!(
+                        "\n\nWARNING: reset did NOT restore deleted file {}",
+                        actual_path.display()
+                    ));
+                }
+                return Err(err);
+            }
+        };
+        state.invalidate_path(&actual_path);
+        state.unlock_path(&actual_path, session.as_deref());
+        remove_draft(config, &actual_path).await;
+        METRICS.deletes.inc();
+        notify_webhook(
+            config,
+            &state.client,
+            "delete",
+            &actual_path,
+            form.get("note").map(|s| s.as_str()),
+            user.as_deref(),
+            &create_revision_output,
+        );
+
+        revision_response(
+            &headers,
+            format!("deleted {}{}", actual_path.display(), writing.wait_note()),
+            None,
+            &create_revision_output,
+        )
+    } else {
+        check_links(config, Some(&actual_path), &content).await?;
+        let writing = lock_writes(config, state).await?;
+        writing
+            .check_unchanged(config, &actual_path, &before)
+            .await?;
+
+        let (content, set_content_and_create_revision_output) = on_branch(
+            config,
+            state,
+            branch,
+            reset_if_err(
+                config,
+                state,
+                set_content_with_revision(
+                    config,
+                    state,
+                    actual_path.as_path(),
+                    content.as_str(),
+                    form.get("note").map(|s| s.as_str()),
+                    user.as_deref(),
+                    skip_build,
+                    branch,
+                ),
+            ),
+        )
+        .await?;
+        edited(
+            config,
+            state,
+            &actual_path,
+            session.as_deref(),
+            form.get("note").map(|s| s.as_str()),
+            user.as_deref(),
+            &set_content_and_create_revision_output,
+        )
+        .await;
+
+        revision_response(
+            &headers,
+            format!(
+                "wrote to {} ({}){}",
+                actual_path.display(),
+                content_stats(config, &content),
+                writing.wait_note()
+            ),
+            page_url(config, &actual_path),
+            &set_content_and_create_revision_output,
+        )
+    }
+}
+
+/// Text has to be UTF-8 and editable_extensions are all text. Anything else is refused unless
+/// it's in put_content_types, which is written as-is like POST /edit/...?binary. Returns whether
+/// the body is binary.
+#[allow(clippy::result_large_err)]
+fn check_put_content_type(config: &Config, headers: &HeaderMap) -> Result<bool, Response<String>> {
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return Err(response_with_status(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "a Content-Type is required",
+        ));
+    };
+    let content_type = content_type
+        .to_str()
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    if content_type.starts_with("text/")
+        || [
+            "application/json",
+            "application/xml",
+            "application/toml",
+            "application/yaml",
+            // what curl sends with --data-binary
+            "application/x-www-form-urlencoded",
+        ]
+        .contains(&content_type.as_str())
+    {
+        Ok(false)
+    } else if config
+        .put_content_types
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&content_type))
+    {
+        Ok(true)
+    } else {
+        Err(response_with_status(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("won't write {} content", content_type),
+        ))
+    }
+}
+
+async fn put_edit(
+    config: &'static Config,
+    state: &'static State,
+    path: FullPath,
+    headers: HeaderMap,
+    body: warp::hyper::body::Bytes,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(
+        config,
+        headers
+            .get(AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default()),
+    )?;
+    require_role(config, user.as_deref(), Role::Editor, "edit")?;
+    let path_str = path
+        .as_str()
+        .strip_prefix(config.mount_prefix.as_str())
+        .and_then(|path| path.strip_prefix("/edit"))
+        .unwrap();
+    let note = headers
+        .get("x-commit-note")
+        .and_then(|note| note.to_str().ok());
+    if check_put_content_type(config, &headers)? {
+        return put_binary(
+            config,
+            state,
+            path_str,
+            &body,
+            note,
+            user.as_deref(),
+            &headers,
+        )
+        .await;
+    }
+    let actual_path = path_to_file(config, state, &forwarded, path_str).await?;
+    check_protected(config, &actual_path)?;
+
+    check_content_bytes(config, body.len())?;
+    let content =
+        String::from_utf8(body.to_vec()).map_err(|_| four_hundred("content isn't valid UTF-8"))?;
+    validate_front_matter(split_front_matter(&content).0).map_err(four_hundred)?;
+    check_note(config, note)?;
+
+    check_links(config, Some(&actual_path), &content).await?;
+    let before = read_blog_file(config, &actual_path).await.ok();
+    let writing = lock_writes(config, state).await?;
+    writing
+        .check_unchanged(config, &actual_path, &before)
+        .await?;
+
+    let (content, output) = reset_if_err(
+        config,
+        state,
+        set_content_with_revision(
+            config,
+            state,
+            actual_path.as_path(),
+            content.as_str(),
+            note,
+            user.as_deref(),
+            false,
+            None,
+        ),
+    )
+    .await?;
+    edited(
+        config,
+        state,
+        &actual_path,
+        None,
+        note,
+        user.as_deref(),
+        &output,
+    )
+    .await;
+
+    revision_response(
+        &headers,
+        format!(
+            "wrote to {} ({}){}",
+            actual_path.display(),
+            content_stats(config, &content),
+            writing.wait_note()
+        ),
+        page_url(config, &actual_path),
+        &output,
+    )
+}
+
+/// Binary PUTs name the file in blog_dir like POST /edit/...?binary, there's no page to resolve.
+async fn put_binary(
+    config: &'static Config,
+    state: &'static State,
+    path_str: &str,
+    bytes: &[u8],
+    note: Option<&str>,
+    user: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let actual_path = binary_file(config, path_str)?;
+    check_content_bytes(config, bytes.len())?;
+    check_note(config, note)?;
+
+    let writing = lock_writes(config, state).await?;
+    let output = write_binary(config, state, &actual_path, bytes, note, user, false, None).await?;
+    METRICS.edits.inc();
+    notify_webhook(
+        config,
+        &state.client,
+        "edit",
+        &actual_path,
+        note,
+        user,
+        &output,
+    );
+
+    revision_response(
+        headers,
+        format!(
+            "wrote {} bytes to {}{}",
+            bytes.len(),
+            actual_path.display(),
+            writing.wait_note()
+        ),
+        page_url(config, &actual_path),
+        &output,
+    )
+}
+
+async fn form_path_to_file(
+    config: &Config,
+    state: &State,
+    forwarded: &Forwarded,
+    form: &HashMap<String, String>,
+) -> Result<PathBuf, Response<String>> {
+    let Some(path) = form.get("path") else {
+        return Err(four_hundred("missing path"));
+    };
+    let path = path
+        .strip_prefix(config.mount_prefix.as_str())
+        .unwrap_or(path);
+    let path_str = path.strip_prefix("/edit").unwrap_or(path);
+
+    path_to_file(config, state, forwarded, path_str).await
+}
+
+/// Builds the site with the edit applied to a copy of blog_dir, leaving blog_dir and dest_dir
+/// alone. The built site is served from /preview-site/ until preview_ttl_secs runs out.
+async fn post_edit_preview(
+    config: &Config,
+    state: &State,
+    form: HashMap<String, String>,
+    forwarded: Forwarded,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(config, authorization.as_deref())?;
+    require_role(config, user.as_deref(), Role::Editor, "preview")?;
+    let Some(preview_dir) = &config.preview_dir else {
+        return Err(four_hundred("previews are not configured"));
+    };
+    let Ok(build_dir) = config.blog_build_dir.strip_prefix(&config.blog_dir) else {
+        return Err(five_hundred("previews need blog_build_dir inside blog_dir"));
+    };
+
+    let actual_path = form_path_to_file(config, state, &forwarded, &form).await?;
+    let content = match (form.get("body"), form.get("content")) {
+        (Some(body), _) => join_front_matter(
+            form.get("front_matter")
+                .map(|s| s.as_str())
+                .unwrap_or_default(),
+            body,
+        ),
+        (None, Some(content)) => content.clone(),
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+    validate_front_matter(split_front_matter(&content).0).map_err(four_hundred)?;
+
+    // unguessable, the preview is served to anyone who has the URL
+    let id = random_token();
+    let source_dir = std::env::temp_dir().join(format!("editor-preview-{}", id));
+    let result = async {
+        tokio::fs::create_dir_all(&source_dir)
+            .await
+            .map_err(five_hundred)?;
+        {
+            // so an edit halfway through doesn't end up in the copy
+            let _writing = state.writes.lock().await;
+            let mut entries = tokio::fs::read_dir(&config.blog_dir)
+                .await
+                .map_err(five_hundred)?;
+            while let Some(entry) = entries.next_entry().await.map_err(five_hundred)? {
+                let path = entry.path();
+                if entry.file_name() == ".git" || path == config.blog_build_dir {
+                    continue;
+                }
+                copy_recursive(&path, &source_dir.join(entry.file_name()), true).await?;
+            }
+        }
+        tokio::fs::write(
+            source_dir.join(actual_path.strip_prefix(&config.blog_dir).unwrap()),
+            encode_content(config, &normalize_content(config, &content))?,
+        )
+        .await
+        .map_err(five_hundred)?;
+
+        let output =
+            command_stdout_in(&source_dir, config.build_command.iter().map(|s| s.as_str())).await?;
+        // temp_dir may be on another filesystem, so no renaming
+        copy_recursive(&source_dir.join(build_dir), &preview_dir.join(&id), false).await?;
+        Ok(output)
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&source_dir).await;
+    let output = result?;
+
+    let preview = preview_dir.join(&id);
+    let ttl = Duration::from_secs(config.preview_ttl_secs);
+    tokio::spawn(async move {
+        tokio::time::sleep(ttl).await;
+        let _ = tokio::fs::remove_dir_all(preview).await;
+    });
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!(
+            "preview of {} at {} for {}s\n\n{}",
+            actual_path.display(),
+            route_url(config, &forwarded.url, &format!("preview-site/{}/", id)),
+            config.preview_ttl_secs,
+            output
+        ))
+        .unwrap())
+}
+
+async fn post_edit_autosave(
+    config: &Config,
+    state: &State,
+    form: HashMap<String, String>,
+    session: Option<String>,
+    forwarded: Forwarded,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(config, authorization.as_deref())?;
+    require_role(config, user.as_deref(), Role::Editor, "edit")?;
+    if config.drafts_dir.is_none() {
+        return Err(four_hundred("drafts are not configured"));
+    }
+
+    let session = session.unwrap_or_else(|| forwarded.client.clone());
+    if let Some(wait) = state.autosave_wait(config, &session) {
+        return Err(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, wait.as_secs().max(1))
+            .body(String::from("autosaving too often"))
+            .unwrap());
+    }
+
+    let actual_path = form_path_to_file(config, state, &forwarded, &form).await?;
+    let content = match (form.get("body"), form.get("content")) {
+        (Some(body), _) => join_front_matter(
+            form.get("front_matter")
+                .map(|s| s.as_str())
+                .unwrap_or_default(),
+            body,
+        ),
+        (None, Some(content)) => content.clone(),
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+    check_content_bytes(config, content.len())?;
+
+    let autosave_path = autosave_path(config, &actual_path)?;
+    if let Some(parent) = autosave_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(five_hundred)?;
+    }
+    tokio::fs::write(&autosave_path, normalize_content(config, &content))
+        .await
+        .map_err(five_hundred)?;
+
+    Ok(response_with_status(StatusCode::NO_CONTENT, ""))
+}
+
+/// Editors can release their own session's lock, admins anyone's.
+async fn post_edit_unlock(
+    config: &Config,
+    state: &State,
+    form: HashMap<String, String>,
+    session: Option<String>,
+    authorization: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(config, authorization.as_deref())?;
+    require_role(config, user.as_deref(), Role::Editor, "unlock")?;
+    let actual_path = form_path_to_file(config, state, &forwarded, &form).await?;
+
+    let admin = user
+        .as_ref()
+        .is_some_and(|user| config.admins.contains(user));
+    let session = if admin {
+        None
+    } else {
+        Some(session.unwrap_or_default())
+    };
+    if state.unlock_path(&actual_path, session.as_deref()) {
+        println!(
+            "{} ({}) released edit lock on {}",
+            user.as_deref().unwrap_or("anonymous"),
+            forwarded.client,
+            actual_path.display()
+        );
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!("unlocked {}", actual_path.display()))
+            .unwrap())
+    } else if state.is_locked(&actual_path) {
+        Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            format!(
+                "{} is locked by another session, only an admin can release it",
+                actual_path.display()
+            ),
+        ))
+    } else {
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!("{} wasn't locked", actual_path.display()))
+            .unwrap())
+    }
+}
+
+async fn save_schedule(
+    config: &Config,
+    schedule: &[ScheduledPost],
+) -> Result<(), Response<String>> {
+    let Some(schedule_file) = &config.schedule_file else {
+        return Err(five_hundred("no schedule_file configured"));
+    };
+
+    let schedule = serde_json::to_string_pretty(schedule).map_err(five_hundred)?;
+    tokio::fs::write(schedule_file, schedule)
+        .await
+        .map_err(five_hundred)
+}
+
+async fn publish_scheduled(config: &'static Config, state: &'static State, post: ScheduledPost) {
+    let wait = (post.publish_at - Utc::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(wait).await;
+
+    let _writing = state.writes.lock().await;
+    let result = match check_degraded(state) {
+        Ok(()) => create_new(&post.path).await,
+        Err(err) => Err(err),
+    };
+    let result = match result {
+        Ok(()) => {
+            reset_if_err(
+                config,
+                state,
+                set_content_with_revision(
+                    config,
+                    state,
+                    &post.path,
+                    &post.content,
+                    post.note.as_deref(),
+                    post.user.as_deref(),
+                    false,
+                    None,
+                ),
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    let keep = match result {
+        Ok((_, output)) => {
+            METRICS.publishes.inc();
+            notify_webhook(
+                config,
+                &state.client,
+                "publish",
+                &post.path,
+                post.note.as_deref(),
+                post.user.as_deref(),
+                &output,
+            );
+            println!("published scheduled post {}", post.path.display());
+            false
+        }
+        Err(err) => {
+            println!(
+                "couldn't publish scheduled post {}: {}",
+                post.path.display(),
+                err.body()
+            );
+            keep_unpublished(config, &post).await
+        }
+    };
+
+    let mut schedule = state.schedule.lock().await;
+    if !keep {
+        schedule.retain(|scheduled| scheduled != &post);
+    }
+    if let Err(err) = save_schedule(config, &schedule).await {
+        println!("couldn't save schedule: {}", err.body());
+    }
+}
+
+/// Saves a scheduled post that couldn't be published as a draft so its content isn't lost. If
+/// there are no drafts, or one is already there, it has to stay in the schedule instead.
+async fn keep_unpublished(config: &Config, post: &ScheduledPost) -> bool {
+    if let Ok(draft_path) = draft_path(config, &post.path) {
+        if !tokio::fs::try_exists(&draft_path).await.unwrap_or(true) {
+            match save_draft(config, &post.path, &post.content).await {
+                Ok(draft_path) => {
+                    println!(
+                        "saved scheduled post {} as a draft at {}",
+                        post.path.display(),
+                        draft_path.display()
+                    );
+                    return false;
+                }
+                Err(err) => println!(
+                    "couldn't save scheduled post {} as a draft: {}",
+                    post.path.display(),
+                    err.body()
+                ),
+            }
+        }
+    }
+
+    println!("keeping {} in the schedule", post.path.display());
+    true
+}
+
+async fn post_check_links(
+    config: &Config,
+    form: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let content = match (form.get("body"), form.get("content")) {
+        (Some(body), _) => body,
+        (None, Some(content)) => content,
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+
+    let relative_to = match form.get("filename") {
+        Some(filename) => Some(contained_path(&config.blog_dir, filename)?),
+        None => None,
+    };
+
+    let dead = dead_links(config, relative_to.as_deref(), content).await;
+    if dead.is_empty() {
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(String::from("no dead links"))
+            .unwrap())
+    } else {
+        Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!("dead links:\n{}", dead.join("\n")))
+            .unwrap())
+    }
+}
+
+/// Renders markdown for the edit page's preview pane. It goes straight into a page on this origin,
+/// so it's sanitized unless sanitize_render is turned off.
+async fn post_edit_render(
+    config: &Config,
+    form: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let body = match (form.get("body"), form.get("content")) {
+        (Some(body), _) => body,
+        (None, Some(content)) => split_front_matter(content).1,
+        (None, None) => return Err(four_hundred("no content from form?")),
+    };
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(
+        &mut html,
+        pulldown_cmark::Parser::new_ext(body, pulldown_cmark::Options::all()),
+    );
+    if config.sanitize_render {
+        html = sanitize_html(config, &html);
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(html)
+        .unwrap())
+}
+
+/// ammonia's defaults, or render_allowed_tags instead of its tags if set, plus
+/// render_allowed_attributes on any tag.
+fn sanitize_html(config: &Config, html: &str) -> String {
+    let mut builder = ammonia::Builder::default();
+    if !config.render_allowed_tags.is_empty() {
+        let tags = config
+            .render_allowed_tags
+            .iter()
+            .map(|tag| tag.as_str())
+            .collect::<HashSet<_>>();
+        // ammonia refuses to both allow a tag and strip its contents, like it does for script
+        builder.rm_clean_content_tags(&tags);
+        builder.tags(tags);
+    }
+    if config
+        .render_allowed_attributes
+        .iter()
+        .any(|attribute| attribute == "rel")
+    {
+        builder.link_rel(None);
+    }
+    builder.add_generic_attributes(
+        config
+            .render_allowed_attributes
+            .iter()
+            .map(|attribute| attribute.as_str()),
+    );
+    builder.clean(html).to_string()
+}
+
+async fn post_publish(
+    config: &'static Config,
+    state: &'static State,
+    form: HashMap<String, String>,
+    headers: HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(
+        config,
+        headers
+            .get(AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default()),
+    )?;
+    // editors can write drafts, they just can't publish them
+    let on = |name: &str| form.get(name).map(|s| s.as_str()) == Some("on");
+    if on("draft") || on("dry_run") {
+        require_role(config, user.as_deref(), Role::Editor, "edit")?;
+    } else {
+        require_role(config, user.as_deref(), Role::Publisher, "publish")?;
+    }
+    let Some(filename) = form.get("filename") else {
+        return Err(four_hundred("missing filename"));
+    };
+
+    let (filename, renamed) = if config.slugify_filename {
+        let slugged = slugify_filename(filename)?;
+        let renamed = if &slugged != filename {
+            format!("renamed {} to {}\n", filename, slugged)
+        } else {
+            String::new()
+        };
+        (slugged, renamed)
+    } else {
+        (filename.clone(), String::new())
+    };
+
+    let actual_path = contained_path(&config.blog_dir, &filename)?;
+    check_editable(config, &actual_path)?;
+    check_protected(config, &actual_path)?;
+
+    if form.get("dry_run").map(|s| s.as_str()) == Some("on") {
+        return dry_run_publish(config, &headers, &actual_path, renamed).await;
+    }
+
+    let content = if form.get("publish_draft").map(|s| s.as_str()) == Some("on") {
+        load_draft(config, &actual_path).await?
+    } else {
+        match form.get("content") {
+            Some(content) => content.clone(),
+            None => return Err(four_hundred("missing content")),
+        }
+    };
+    validate_front_matter(split_front_matter(&content).0).map_err(four_hundred)?;
+
+    if tokio::fs::try_exists(&actual_path)
+        .await
+        .map_err(five_hundred)?
+    {
+        return Err(four_hundred("already exists"));
+    }
+
+    if form.get("draft").map(|s| s.as_str()) == Some("on") {
+        let draft_path = save_draft(config, &actual_path, &content).await?;
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!(
+                "{}saved draft of {} to {}",
+                renamed,
+                actual_path.display(),
+                draft_path.display()
+            ))
+            .unwrap());
+    }
+
+    check_note(config, form.get("note").map(|s| s.as_str()))?;
+    check_links(config, Some(&actual_path), &content).await?;
+
+    let publish_at = match form.get("publish_at").map(|s| s.trim()) {
+        Some(publish_at) if !publish_at.is_empty() => Some(
+            DateTime::parse_from_rfc3339(publish_at)
+                .map_err(|err| four_hundred(format!("invalid publish_at: {}", err)))?
+                .with_timezone(&Utc),
+        ),
+        _ => None,
+    };
+
+    if let Some(publish_at) = publish_at.filter(|publish_at| *publish_at > Utc::now()) {
+        if config.schedule_file.is_none() {
+            return Err(four_hundred(
+                "scheduling requires schedule_file to be configured",
+            ));
+        }
+
+        let post = ScheduledPost {
+            path: actual_path.clone(),
+            content: content.clone(),
+            note: form.get("note").cloned(),
+            user: user.clone(),
+            publish_at,
+        };
+
+        let mut schedule = state.schedule.lock().await;
+        if schedule
+            .iter()
+            .any(|scheduled| scheduled.path == actual_path)
+        {
+            return Err(four_hundred("already scheduled"));
+        }
+        schedule.push(post.clone());
+        save_schedule(config, &schedule).await?;
+        remove_draft(config, &actual_path).await;
+        tokio::spawn(publish_scheduled(config, state, post));
+
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!(
+                "{}scheduled {} for {}",
+                renamed,
+                actual_path.display(),
+                publish_at.to_rfc3339()
+            ))
+            .unwrap());
+    }
+
+    let skip_build = skip_build(config, &form)?;
+    let branch = branch(config, &form)?;
+
+    let writing = lock_writes(config, state).await?;
+    let published = async {
+        // claim the filename atomically so a concurrent publish can't overwrite it
+        create_new(&actual_path).await?;
+        reset_if_err(
+            config,
+            state,
+            set_content_with_revision(
+                config,
+                state,
+                actual_path.as_path(),
+                content.as_str(),
+                form.get("note").map(|s| s.as_str()),
+                user.as_deref(),
+                skip_build,
+                branch,
+            ),
+        )
+        .await
+    };
+    let (_, stdout) = on_branch(config, state, branch, published).await?;
+    remove_draft(config, &actual_path).await;
+    METRICS.publishes.inc();
+    notify_webhook(
+        config,
+        &state.client,
+        "publish",
+        &actual_path,
+        form.get("note").map(|s| s.as_str()),
+        user.as_deref(),
+        &stdout,
+    );
+
+    revision_response(
+        &headers,
+        format!(
+            "{}wrote to {}{}",
+            renamed,
+            actual_path.display(),
+            writing.wait_note()
+        ),
+        page_url(config, &actual_path),
+        &stdout,
+    )
+}
+
+#[derive(Serialize)]
+struct DryRun {
+    path: String,
+    relative_path: String,
+    exists: bool,
+}
+
+/// Where a publish would write, without writing anything.
+async fn dry_run_publish(
+    config: &Config,
+    headers: &HeaderMap,
+    actual_path: &Path,
+    renamed: String,
+) -> Result<Response<String>, Response<String>> {
+    let dry_run = DryRun {
+        path: actual_path.display().to_string(),
+        relative_path: actual_path
+            .strip_prefix(&config.blog_dir)
+            .unwrap()
+            .display()
+            .to_string(),
+        exists: tokio::fs::try_exists(actual_path)
+            .await
+            .map_err(five_hundred)?,
+    };
+
+    if wants_json(headers) {
+        let body = serde_json::to_string(&dry_run).map_err(five_hundred)?;
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap());
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!(
+            "{}would write to {}{}",
+            renamed,
+            dry_run.path,
+            if dry_run.exists {
+                ", which already exists"
+            } else {
+                ""
+            }
+        ))
+        .unwrap())
+}
+
+async fn post_upload(
+    config: &'static Config,
+    state: &'static State,
+    mut form: FormData,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(config, authorization.as_deref())?;
+    require_role(config, user.as_deref(), Role::Editor, "upload")?;
+    let Some(media_dir) = &config.media_dir else {
+        return Err(four_hundred("uploads are not configured"));
+    };
+
+    let mut file = None;
+    let mut note = None;
+    while let Some(part) = form.try_next().await.map_err(four_hundred)? {
+        let name = part.name().to_string();
+        let filename = part.filename().map(String::from);
+
+        let mut data = Vec::new();
+        let mut stream = part.stream();
+        while let Some(chunk) = stream.try_next().await.map_err(four_hundred)? {
+            data.extend_from_slice(chunk.chunk());
+        }
+
+        match (name.as_str(), filename) {
+            ("file", Some(filename)) => file = Some((filename, data)),
+            ("note", _) => note = Some(String::from_utf8_lossy(&data).into_owned()),
+            _ => {}
+        }
+    }
+
+    let Some((filename, data)) = file else {
+        return Err(four_hundred("no file from form?"));
+    };
+    check_note(config, note.as_deref())?;
+
+    let extension = Path::new(&filename)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !config.media_extensions.contains(&extension) {
+        return Err(four_hundred(format!(
+            "can't upload .{} files, allowed extensions are {}",
+            extension,
+            config.media_extensions.join(", ")
+        )));
+    }
+
+    let actual_path = contained_path(media_dir, &filename)?;
+    check_editable_prefix(config, &actual_path)?;
+    check_protected(config, &actual_path)?;
+    if tokio::fs::try_exists(&actual_path)
+        .await
+        .map_err(five_hundred)?
+    {
+        return Err(four_hundred("already exists"));
+    }
+
+    let relative_path = actual_path.strip_prefix(media_dir).unwrap();
+    let media_url = format!(
+        "{}{}",
+        config.media_url,
+        relative_path.to_string_lossy().replace('\\', "/")
+    );
+
+    let writing = lock_writes(config, state).await?;
+    let stdout = reset_if_err(config, state, async {
+        if let Some(parent) = actual_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(five_hundred)?;
+        }
+        tokio::fs::write(&actual_path, &data)
+            .await
+            .map_err(five_hundred)?;
+
+        let message = commit_message(
+            config,
+            "upload",
+            &[&actual_path],
+            note.as_deref(),
+            user.as_deref(),
+        )?;
+        create_revision(config, state, &[&actual_path], message, false, None).await
+    })
+    .await?;
+
+    notify_webhook(
+        config,
+        &state.client,
+        "upload",
+        &actual_path,
+        note.as_deref(),
+        user.as_deref(),
+        &stdout,
+    );
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!(
+            "uploaded {}{}\n\n{}",
+            media_url,
+            writing.wait_note(),
+            stdout
+        ))
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct BatchEdit {
+    path: String,
+    content: String,
+}
+
+/// Writes every file and commits them as one revision. Every path is checked before anything is
+/// written, and a failure partway through resets all of them.
+async fn post_api_batch(
+    config: &'static Config,
+    state: &'static State,
+    edits: Vec<BatchEdit>,
+    headers: HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let user = authenticate(
+        config,
+        headers
+            .get(AUTHORIZATION)
+            .map(|authorization| authorization.to_str().unwrap_or_default()),
+    )?;
+    require_role(config, user.as_deref(), Role::Editor, "edit")?;
+    let note = headers
+        .get("x-commit-note")
+        .and_then(|note| note.to_str().ok());
+    check_note(config, note)?;
+    if edits.is_empty() {
+        return Err(four_hundred("no edits"));
+    }
+
+    let mut actual_paths = Vec::new();
+    for edit in &edits {
+        let actual_path = contained_path(&config.blog_dir, edit.path.trim_start_matches('/'))?;
+        // new files can't be canonicalized, existing ones might be symlinks out of blog_dir
+        if let Ok(canonical_path) = actual_path.canonicalize() {
+            if !canonical_path.starts_with(&config.blog_dir) {
+                return Err(outside_allowed(None, &canonical_path, &config.blog_dir));
+            }
+        }
+        check_editable(config, &actual_path)?;
+        check_protected(config, &actual_path)?;
+        validate_front_matter(split_front_matter(&edit.content).0)
+            .map_err(|err| four_hundred(format!("{}: {}", edit.path, err)))?;
+        check_links(config, Some(&actual_path), &edit.content).await?;
+        actual_paths.push(actual_path);
+    }
+    let relative_paths = actual_paths
+        .iter()
+        .map(|actual_path| {
+            actual_path
+                .strip_prefix(&config.blog_dir)
+                .unwrap()
+                .display()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let writing = lock_writes(config, state).await?;
+    let output = reset_if_err(config, state, async {
+        let mut format_output = String::new();
+        for (edit, actual_path) in edits.iter().zip(&actual_paths) {
+            format_output += &write_content(config, actual_path, &edit.content).await?.1;
+        }
+        let actual_paths = actual_paths.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+        let message = commit_message(config, "edit", &actual_paths, note, user.as_deref())?;
+        let mut output =
+            create_revision(config, state, &actual_paths, message, false, None).await?;
+        output.format = format_output;
+        Ok(output)
+    })
+    .await?;
+
+    for actual_path in &actual_paths {
+        edited(
+            config,
+            state,
+            actual_path,
+            None,
+            note,
+            user.as_deref(),
+            &output,
+        )
+        .await;
+    }
+
+    revision_response(
+        &headers,
+        format!("wrote to {}{}", relative_paths, writing.wait_note()),
+        None,
+        &output,
+    )
+}
+
+#[derive(Serialize)]
+struct ApiContent {
+    path: String,
+    content: String,
+    hash: String,
+}
+
+async fn get_api_content(
+    config: &Config,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    let Some(path) = query.get("path") else {
+        return Err(four_hundred("missing path"));
+    };
+
+    let actual_path = blog_file(config, path)?;
+    let content = read_blog_file(config, &actual_path)
+        .await
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::InvalidData => five_hundred(err),
+            _ => response_with_status(StatusCode::NOT_FOUND, format!("no such file {}", path)),
+        })?;
+    let body = serde_json::to_string(&ApiContent {
+        path: actual_path
+            .strip_prefix(&config.blog_dir)
+            .unwrap()
+            .display()
+            .to_string(),
+        hash: content_hash(content.as_bytes()),
+        content,
+    })
+    .map_err(five_hundred)?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap())
+}
+
+/// Runs show_file_at_revision, 404ing if the file or the revision doesn't exist.
+async fn file_at_revision(
+    config: &Config,
+    relative_path: &str,
+    revision: &str,
+) -> Result<String, Response<String>> {
+    command_stdout(
+        config,
+        config
+            .show_file_at_revision
+            .iter()
+            .map(|s| s.as_str())
+            .chain([format!("{}:{}", revision, relative_path).as_str()]),
+    )
+    .await
+    .map_err(|err| {
+        // what git says when the revision exists but the path didn't in it
+        if err.body().contains("does not exist in") || err.body().contains("but not in") {
+            response_with_status(
+                StatusCode::NOT_FOUND,
+                format!("{} didn't exist at {}", relative_path, revision),
+            )
+        } else if err.body().contains("invalid object name") {
+            response_with_status(StatusCode::NOT_FOUND, format!("no revision {}", revision))
+        } else {
+            err
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct ApiContentAt {
+    path: String,
+    revision: String,
+    content: String,
+}
+
+/// A file as it was at a revision. It may not exist anymore, so it's only checked to be inside
+/// blog_dir rather than resolved.
+async fn get_api_content_at(
+    config: &Config,
+    query: HashMap<String, String>,
+    headers: HeaderMap,
+) -> Result<Response<String>, Response<String>> {
+    let Some(path) = query.get("path") else {
+        return Err(four_hundred("missing path"));
+    };
+    let Some(revision_name) = query.get("revision") else {
+        return Err(four_hundred("missing revision"));
+    };
+    let revision = revision_hash(revision_name)?;
+
+    let actual_path = contained_path(&config.blog_dir, path.trim_start_matches('/'))?;
+    check_editable(config, &actual_path)?;
+    let relative_path = actual_path
+        .strip_prefix(&config.blog_dir)
+        .unwrap()
+        .display()
+        .to_string();
+
+    let content = file_at_revision(config, &relative_path, revision).await?;
+
+    if !wants_json(&headers) {
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(content)
+            .unwrap());
+    }
+
+    let body = serde_json::to_string(&ApiContentAt {
+        path: relative_path,
+        revision: revision.to_string(),
+        content,
+    })
+    .map_err(five_hundred)?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap())
+}
+
+#[derive(Serialize)]
+struct ApiRevisions {
+    page: usize,
+    per_page: usize,
+    total: usize,
+    revisions: Vec<Revision>,
+}
+
+/// What the authenticated user may do, so clients can hide what they can't.
+#[derive(Serialize)]
+struct Whoami {
+    user: String,
+    role: Role,
+    admin: bool,
+    can_edit: bool,
+    can_publish: bool,
+    can_revert: bool,
+    can_upload: bool,
+    can_skip_build: bool,
+}
+
+async fn get_api_whoami(
+    config: &Config,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    let user = require_user(config, authorization.as_deref())?;
+
+    let role = role(config, Some(&user));
+
+    let body = serde_json::to_string(&Whoami {
+        admin: config.admins.contains(&user),
+        user,
+        role,
+        can_edit: role >= Role::Editor,
+        can_publish: role >= Role::Publisher,
+        can_revert: role >= Role::Publisher,
+        can_upload: role >= Role::Editor && config.media_dir.is_some(),
+        can_skip_build: role >= Role::Editor && config.allow_skip_build,
+    })
+    .map_err(five_hundred)?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap())
+}
+
+async fn get_api_revisions(
+    config: &Config,
+    query: HashMap<String, String>,
+) -> Result<Response<String>, Response<String>> {
+    #[allow(clippy::result_large_err)]
+    let number = |name: &str, default: usize| match query.get(name) {
+        Some(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|value| *value > 0)
+            .ok_or_else(|| four_hundred(format!("invalid {} {}", name, value))),
+        None => Ok(default),
+    };
+    let page = number("page", 1)?;
+    let per_page = number("per_page", 20)?.min(100);
+
+    let revisions_output = command_stdout(
+        config,
+        config.structured_revisions.iter().map(|s| s.as_str()),
+    )
+    .await?;
+    let revisions = parse_revisions(&revisions_output);
+    let total = revisions.len();
+
+    let body = serde_json::to_string(&ApiRevisions {
+        page,
+        per_page,
+        total,
+        revisions: revisions
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    })
+    .map_err(five_hundred)?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap())
+}
+
+async fn get_admin_status(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    require_admin(config, authorization.as_deref())?;
+
+    let mut context = base_context(config);
+    if let Some(status_command) = &config.status_command {
+        let status = command_stdout(config, status_command.iter().map(|s| s.as_str())).await?;
+        context.insert("status", &status);
+    }
+    context.insert("degraded", &*state.degraded.read().unwrap());
+    context.insert("revisions", &count_revisions(config).await.ok());
+    context.insert("max_revisions", &config.max_revisions);
+    let page = render_template(tera, "status.html", &context)
+        .map_err(|err| render_error(config, "status.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+async fn get_admin_diff(
+    config: &Config,
+    tera: &RwLock<Tera>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    require_admin(config, authorization.as_deref())?;
+
+    let diff = command_stdout(config, config.diff_command.iter().map(|s| s.as_str())).await?;
+
+    let mut context = base_context(config);
+    context.insert("diff", &diff);
+    let page = render_template(tera, "working_diff.html", &context)
+        .map_err(|err| render_error(config, "working_diff.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+async fn post_admin_discard(
+    config: &Config,
+    state: &State,
+    authorization: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = require_admin(config, authorization.as_deref())?;
+    println!(
+        "{} ({}) discarding uncommitted changes",
+        user, forwarded.client
+    );
+
+    // not lock_writes, discarding is how a degraded tree gets fixed
+    let _writing = state.writes.lock().await;
+    let reset_output =
+        command_stdout(config, config.reset_command.iter().map(|s| s.as_str())).await?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("discarded uncommitted changes\n\n{}", reset_output))
+        .unwrap())
+}
+
+async fn post_admin_rebuild(
+    config: &'static Config,
+    state: &State,
+    authorization: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = require_admin(config, authorization.as_deref())?;
+    println!("{} ({}) rebuilding", user, forwarded.client);
+
+    let writing = lock_writes(config, state).await?;
+    let mut output = RevisionOutput::default();
+    if config.sitemap_before_build {
+        output.sitemap = regenerate_sitemap(config).await;
+    }
+    output.build = rebuild(config, state).await?;
+    if !config.sitemap_before_build {
+        output.sitemap = regenerate_sitemap(config).await;
+    }
+    output.copy = copy_build(config).await?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("rebuilt{}\n\n{}", writing.wait_note(), output))
+        .unwrap())
+}
+
+/// Runs promote_command with the branch, e.g. a fast-forward merge of a reviewed staging branch,
+/// then builds the result.
+async fn post_admin_promote(
+    config: &'static Config,
+    state: &State,
+    form: HashMap<String, String>,
+    authorization: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = require_admin(config, authorization.as_deref())?;
+    let Some(promote_command) = &config.promote_command else {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            "promoting a branch isn't set up",
+        ));
+    };
+    let Some(branch) = form
+        .get("branch")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    else {
+        return Err(four_hundred("no branch to promote?"));
+    };
+    check_branch_name(branch)?;
+    println!("{} ({}) promoting {}", user, forwarded.client, branch);
+
+    let writing = lock_writes(config, state).await?;
+    let promoted = reset_if_err(
+        config,
+        state,
+        command_stdout(
+            config,
+            promote_command.iter().map(|s| s.as_str()).chain([branch]),
+        ),
+    )
+    .await?;
+    let mut output = RevisionOutput {
+        commit: promoted,
+        ..Default::default()
+    };
+    if config.sitemap_before_build {
+        output.sitemap = regenerate_sitemap(config).await;
+    }
+    output.build = rebuild(config, state).await?;
+    if !config.sitemap_before_build {
+        output.sitemap = regenerate_sitemap(config).await;
+    }
+    output.copy = copy_build(config).await?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!(
+            "promoted {}{}\n\n{}",
+            branch,
+            writing.wait_note(),
+            output
+        ))
+        .unwrap())
+}
+
+async fn post_admin_clear_degraded(
+    config: &Config,
+    state: &State,
+    authorization: Option<String>,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    let user = require_admin(config, authorization.as_deref())?;
+
+    let Some(reason) = state.degraded.write().unwrap().take() else {
+        return Err(four_hundred("not degraded"));
+    };
+    println!(
+        "{} ({}) cleared degraded state: {}",
+        user, forwarded.client, reason
+    );
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(String::from("cleared, writes are allowed again"))
+        .unwrap())
+}
+
+async fn get_admin_build_status(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    require_admin(config, authorization.as_deref())?;
+
+    let build_status = state.build_status.read().unwrap().clone();
+    let (queued, pending) = {
+        let builds = state.builds.lock().unwrap();
+        (builds.queued_at.is_some(), builds.pending)
+    };
+
+    let mut context = base_context(config);
+    context.insert("build", &build_status);
+    context.insert("queued", &queued);
+    context.insert("pending", &pending);
+    context.insert("max_pending_builds", &config.max_pending_builds);
+    context.insert("busy", &state.writes.try_lock().is_err());
+    context.insert(
+        "writes_queued",
+        &state.writes_queued.load(Ordering::Relaxed),
+    );
+    context.insert("built", &build_status.success.is_some());
+    context.insert(
+        "started_at",
+        &build_status
+            .started_at
+            .map(|started_at| started_at.to_rfc3339()),
+    );
+    let page = render_template(tera, "build_status.html", &context)
+        .map_err(|err| render_error(config, "build_status.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+async fn get_admin_scheduled(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    require_admin(config, authorization.as_deref())?;
+
+    let scheduled = state
+        .schedule
+        .lock()
+        .await
+        .iter()
+        .map(|post| {
+            (
+                post.path
+                    .strip_prefix(&config.blog_dir)
+                    .unwrap_or(&post.path)
+                    .display()
+                    .to_string(),
+                post.publish_at.to_rfc3339(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut context = base_context(config);
+    context.insert("scheduled", &scheduled);
+    let page = render_template(tera, "scheduled.html", &context)
+        .map_err(|err| render_error(config, "scheduled.html", err))?;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .unwrap())
+}
+
+async fn post_admin_reload_templates(
+    config: &Config,
+    state: &State,
+    tera: &RwLock<Tera>,
+    authorization: Option<String>,
+) -> Result<Response<String>, Response<String>> {
+    require_admin(config, authorization.as_deref())?;
+
+    *tera.write().unwrap() = load_templates(config).map_err(five_hundred)?;
+    *state.publish_page.lock().unwrap() = None;
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(String::from("reloaded templates"))
+        .unwrap())
+}
+
+async fn get_metrics(
+    config: &Config,
+    state: &State,
+    forwarded: Forwarded,
+) -> Result<Response<String>, Response<String>> {
+    if !config.metrics_allowlist.is_empty() && !config.metrics_allowlist.contains(&forwarded.client)
+    {
+        return Err(response_with_status(
+            StatusCode::FORBIDDEN,
+            format!("{} may not read metrics", forwarded.client),
+        ));
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", TEXT_FORMAT)
+        .body(METRICS.render(state))
+        .unwrap())
+}
+
+/// For load balancers and monitoring: 503 while degraded is set, the reason is only on
+/// /admin/status.
+async fn get_health(state: &State) -> Result<Response<String>, Response<String>> {
+    if state.degraded.read().unwrap().is_some() {
+        return Err(response_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "degraded, writes are refused until an admin clears it",
+        ));
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(String::from("ok"))
+        .unwrap())
+}
+
+fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Matches when the query string has name in it, with or without a value.
+fn query_flag(name: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and_then(move |query: HashMap<String, String>| async move {
+            if query.contains_key(name) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Identifies a request in its error body, the `X-Request-Id` header, and the server log, so an
+/// error someone reports can be matched up with what the server printed. A proxy in front may
+/// assign one already, in which case it's kept.
+#[derive(Clone)]
+struct RequestId(String);
+
+impl RequestId {
+    fn new(headers: &HeaderMap) -> RequestId {
+        let incoming = headers
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+
+        match incoming {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(random_token()),
+        }
+    }
+
+    fn suffix(&self) -> String {
+        format!(" (request id: {})", self.0)
+    }
+}
+
+/// Adds the request id to the response headers, and to the end of plain text error bodies.
+async fn tag_request_id(
+    request_id: Option<RequestId>,
+    response: Response<warp::hyper::Body>,
+) -> Response<warp::hyper::Body> {
+    let Some(request_id) = request_id else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    if let Ok(value) = HeaderValue::try_from(&request_id.0) {
+        parts.headers.insert("x-request-id", value);
+    }
+
+    let plain_text = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/plain"));
+    if !(parts.status.is_client_error() || parts.status.is_server_error()) || !plain_text {
+        return Response::from_parts(parts, body);
+    }
+
+    match warp::hyper::body::to_bytes(body).await {
+        Ok(body) => {
+            let mut body = body.to_vec();
+            body.extend_from_slice(request_id.suffix().as_bytes());
+            Response::from_parts(parts, body.into())
+        }
+        Err(err) => five_hundred(format!("{}{}", err, request_id.suffix())).map(Into::into),
+    }
+}
+
+/// The peer address of a TCP connection, attached to each request since the server is run
+/// through hyper directly rather than `warp::serve`.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+fn forwarded(
+    config: &'static Config,
+) -> impl Filter<Extract = (Forwarded,), Error = Infallible> + Clone {
+    warp::ext::optional()
+        .and(warp::header::headers_cloned())
+        .map(move |remote: Option<RemoteAddr>, headers: HeaderMap| {
+            Forwarded::new(config, remote.map(|remote| remote.0), &headers)
+        })
+}
+
+#[derive(Debug)]
+struct RateLimited(Duration);
+
+impl warp::reject::Reject for RateLimited {}
+
+fn rate_limit(
+    config: &'static Config,
+    state: &'static State,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    forwarded(config)
+        .and_then(move |forwarded: Forwarded| async move {
+            match state.take_token(config, &forwarded.client) {
+                None => Ok(()),
+                Some(wait) => {
+                    println!("rate limiting {} for {:?}", forwarded.client, wait);
+                    Err(warp::reject::custom(RateLimited(wait)))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn rate_limited(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    match rejection.find::<RateLimited>() {
+        Some(RateLimited(wait)) => Ok(Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, wait.as_secs_f64().ceil() as u64)
+            .body(String::from("too many requests"))
+            .unwrap()),
+        None => Err(rejection),
+    }
+}
+
+/// body::form() rejects a JSON or multipart body instead of handing over an empty form, which
+/// would otherwise end up as the catch-all 404.
+async fn form_rejected(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    if rejection.find::<UnsupportedMediaType>().is_some() {
+        return Ok(response_with_status(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "a form body is required, with Content-Type: application/x-www-form-urlencoded",
+        ));
+    }
+    match rejection.find::<BodyDeserializeError>() {
+        Some(err) => Ok(four_hundred(format!(
+            "couldn't parse the form body: {}",
+            err
+        ))),
+        None => Err(rejection),
+    }
+}
+
+/// The multipart filter refuses a body over max_upload_bytes before post_upload sees it, which
+/// would otherwise end up as the catch-all 404.
+async fn upload_rejected(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    match rejection.find::<PayloadTooLarge>() {
+        Some(_) => Ok(response_with_status(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "upload is larger than max_upload_bytes",
+        )),
+        None => Err(rejection),
+    }
+}
+
+/// Recovered inside /api/, whose own catch-all would otherwise turn a malformed body into a 404.
+async fn json_rejected(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    if rejection.find::<UnsupportedMediaType>().is_some() {
+        return Ok(response_with_status(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "a JSON body is required, with Content-Type: application/json",
+        ));
+    }
+    match rejection.find::<BodyDeserializeError>() {
+        Some(err) => Ok(four_hundred(format!(
+            "couldn't parse the JSON body: {}",
+            err
+        ))),
+        None => Err(rejection),
+    }
+}
+
+fn mount(prefix: &str) -> BoxedFilter<()> {
+    prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(String::from(segment))).boxed()
+        })
+}
+
+fn serve_dir(dir: PathBuf) -> impl Filter<Extract = (warp::fs::File,), Error = Rejection> + Clone {
+    let base: &'static Path = Box::leak(Box::new(dir.clone()));
+    warp::fs::dir(dir).and_then(move |file: warp::fs::File| async move {
+        match file.path().canonicalize() {
+            Ok(path) if path.starts_with(base) => Ok(file),
+            _ => Err(warp::reject::not_found()),
+        }
+    })
+}
+
+fn cors(config: &Config) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST"])
+        .allow_headers(["Content-Type", "Authorization"]);
+
+    if config
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        cors.allow_any_origin()
+    } else {
+        let own_origin = config.url.origin().ascii_serialization();
+        cors.allow_origins(
+            config
+                .cors_allowed_origins
+                .iter()
+                .map(|origin| origin.as_str())
+                .chain([own_origin.as_str()]),
+        )
+    }
+}
+
+/// TOML has no null, so null fields in YAML or JSON configs are dropped (leaving them unset)
+/// before converting.
+fn json_to_table(value: serde_json::Value) -> Result<toml::Table, String> {
+    fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(object) => serde_json::Value::Object(
+                object
+                    .into_iter()
+                    .filter(|(_, value)| !value.is_null())
+                    .map(|(key, value)| (key, without_nulls(value)))
+                    .collect(),
+            ),
+            serde_json::Value::Array(array) => {
+                serde_json::Value::Array(array.into_iter().map(without_nulls).collect())
+            }
+            value => value,
+        }
+    }
+
+    serde_json::from_value(without_nulls(value)).map_err(|err| err.to_string())
+}
+
+/// Parses the config file by its extension, TOML unless it's YAML or JSON.
+fn parse_config_table(path: &Path, config_buf: &str) -> Result<toml::Table, String> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(config_buf)
+            .map_err(|err| err.to_string())
+            .and_then(json_to_table),
+        Some("json") => serde_json::from_str(config_buf)
+            .map_err(|err| err.to_string())
+            .and_then(json_to_table),
+        _ => toml::from_str::<toml::Table>(config_buf).map_err(|err| err.to_string()),
+    }
+}
+
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "build_status.html",
+        include_str!("../templates/build_status.html"),
+    ),
+    ("cookie.html", include_str!("../templates/cookie.html")),
+    ("diff_rev.html", include_str!("../templates/diff_rev.html")),
+    ("edit.html", include_str!("../templates/edit.html")),
+    ("feed.xml", include_str!("../templates/feed.xml")),
+    ("macros.html", include_str!("../templates/macros.html")),
+    (
+        "not_found.html",
+        include_str!("../templates/not_found.html"),
+    ),
+    ("publish.html", include_str!("../templates/publish.html")),
+    ("revert.html", include_str!("../templates/revert.html")),
+    (
+        "revert_diff.html",
+        include_str!("../templates/revert_diff.html"),
+    ),
+    (
+        "scheduled.html",
+        include_str!("../templates/scheduled.html"),
+    ),
+    ("search.html", include_str!("../templates/search.html")),
+    ("status.html", include_str!("../templates/status.html")),
+    (
+        "working_diff.html",
+        include_str!("../templates/working_diff.html"),
+    ),
+];
+
+/// Templates from templates_dir, with the built-in defaults filling in any it doesn't have.
+fn load_templates(config: &Config) -> Result<Tera, tera::Error> {
+    let mut defaults = Tera::default();
+    defaults.add_raw_templates(DEFAULT_TEMPLATES.iter().copied())?;
+
+    let Some(templates_dir) = &config.templates_dir else {
+        return Ok(defaults);
+    };
+    let templates_pattern = templates_dir.join("**").join("*.{html,xml}");
+    let mut tera = Tera::parse(&format!("{}", templates_pattern.display()))?;
+    tera.extend(&defaults)?;
+    Ok(tera)
+}
+
+/// Takes an advisory lock on lock_file and writes our PID to it, failing if another instance
+/// holds it. The OS drops the lock when the process dies, so a crash doesn't leave it stuck.
+fn acquire_lock_file(lock_file: &Path) -> Result<std::fs::File, String> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_file)
+        .map_err(|err| format!("couldn't open {}: {}", lock_file.display(), err))?;
+    if file.try_lock_exclusive().is_err() {
+        return Err(format!(
+            "another editor (pid {}) is running on this blog_dir, {} is locked",
+            std::fs::read_to_string(lock_file)
+                .unwrap_or_default()
+                .trim(),
+            lock_file.display()
+        ));
+    }
+    file.set_len(0)
+        .and_then(|_| write!(file, "{}", std::process::id()))
+        .map_err(|err| format!("couldn't write {}: {}", lock_file.display(), err))?;
+    Ok(file)
+}
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Binds before lock_file is taken, so a second instance on the same address stops here.
+fn bind_listener(config: &Config) -> Result<Listener, String> {
+    match (config.bind, &config.bind_unix) {
+        (Some(bind), None) => std::net::TcpListener::bind(bind)
+            .map(Listener::Tcp)
+            .map_err(|err| format!("couldn't bind {}: {}", bind, err)),
+
+        #[cfg(unix)]
+        (None, Some(bind_unix)) => {
+            use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+            if let Ok(metadata) = std::fs::symlink_metadata(bind_unix) {
+                if metadata.file_type().is_socket() {
+                    // a socket something still answers on isn't ours to take over
+                    if std::os::unix::net::UnixStream::connect(bind_unix).is_ok() {
+                        return Err(format!("{} is in use", bind_unix.display()));
+                    }
+                    std::fs::remove_file(bind_unix).map_err(|err| {
+                        format!("couldn't remove {}: {}", bind_unix.display(), err)
+                    })?;
+                }
+            }
+
+            let listener = tokio::net::UnixListener::bind(bind_unix)
+                .map_err(|err| format!("couldn't bind {}: {}", bind_unix.display(), err))?;
+            if let Some(mode) = config.bind_unix_mode {
+                std::fs::set_permissions(bind_unix, std::fs::Permissions::from_mode(mode))
+                    .map_err(|err| {
+                        format!(
+                            "couldn't set permissions on {}: {}",
+                            bind_unix.display(),
+                            err
+                        )
+                    })?;
+            }
+            Ok(Listener::Unix(listener))
+        }
+
+        #[cfg(not(unix))]
+        (None, Some(_)) => Err(String::from("bind_unix is only supported on unix")),
+
+        (Some(_), Some(_)) => Err(String::from("only one of bind and bind_unix may be set")),
+
+        (None, None) => Err(String::from("one of bind or bind_unix must be set")),
+    }
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix, so the server can stop and release lock_file.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+const USAGE: &str = "usage: editor <config> [--bind <addr:port>] [--port <port>] [--selftest]";
+
+struct Args {
+    config: PathBuf,
+    bind: Option<SocketAddr>,
+    port: Option<u16>,
+    selftest: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut config = None;
+        let mut bind = None;
+        let mut port = None;
+        let mut selftest = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--bind" => {
+                    let value = args.next().ok_or("--bind needs an address")?;
+                    bind = Some(
+                        value
+                            .parse()
+                            .map_err(|err| format!("invalid --bind {}: {}", value, err))?,
+                    );
+                }
+                "--port" => {
+                    let value = args.next().ok_or("--port needs a port")?;
+                    port = Some(
+                        value
+                            .parse()
+                            .map_err(|err| format!("invalid --port {}: {}", value, err))?,
+                    );
+                }
+                "--selftest" => selftest = true,
+                _ if arg.starts_with('-') => return Err(format!("unknown flag {}", arg)),
+                _ if config.is_none() => config = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        Ok(Args {
+            config: config.ok_or("missing config path")?,
+            bind,
+            port,
+            selftest,
+        })
+    }
+}
+
+/// Override top-level config fields with `EDITOR_<FIELD>` environment variables, which take
+/// precedence over the config file. Values are parsed as TOML if possible (`EDITOR_USERS='{ me =
+/// "hunter2" }'`, `EDITOR_COMPRESSION=false`) and taken as plain strings otherwise
+/// (`EDITOR_BIND=0.0.0.0:2222`).
+fn env_overrides(config: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let Some(field) = name.strip_prefix("EDITOR_") else {
+            continue;
+        };
+
+        let value = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or(toml::Value::String(raw));
+        config.insert(field.to_lowercase(), value);
+    }
+}
+
+/// Canonicalizes the configured directories, creating the ones the editor owns, and checks they
+/// are where they need to be. Panics on a bad config, like the rest of startup.
+fn resolve_config(config: &mut Config) {
+    config.blog_dir = config.blog_dir.canonicalize().unwrap();
+    config.blog_build_dir = config.blog_build_dir.canonicalize().unwrap();
+    config.dest_dir = config.dest_dir.canonicalize().unwrap();
+    if let Some(templates_dir) = &config.templates_dir {
+        config.templates_dir = Some(templates_dir.canonicalize().unwrap());
+    }
+    if let Some(post_templates_dir) = &config.post_templates_dir {
+        config.post_templates_dir = Some(post_templates_dir.canonicalize().unwrap());
+    }
+    config.mount_prefix = match config.mount_prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("/{}", prefix),
+    };
+    if let Some(media_dir) = &config.media_dir {
+        let media_dir = config.blog_dir.join(media_dir);
+        std::fs::create_dir_all(&media_dir).unwrap();
+        let media_dir = media_dir.canonicalize().unwrap();
+        assert!(
+            media_dir.starts_with(&config.blog_dir),
+            "media_dir must be inside blog_dir"
+        );
+        config.media_dir = Some(media_dir);
+    }
+    if let Some(sitemap_path) = &config.sitemap_path {
+        let sitemap_path = normalize_path(&config.blog_dir.join(sitemap_path));
+        assert!(
+            sitemap_path.starts_with(&config.blog_dir),
+            "sitemap_path must be inside blog_dir"
+        );
+        config.sitemap_path = Some(sitemap_path);
+    }
+    config.touch_after_edit = config
+        .touch_after_edit
+        .iter()
+        .map(|path| {
+            let path = normalize_path(&config.blog_dir.join(path));
+            assert!(
+                path.starts_with(&config.blog_dir),
+                "touch_after_edit must be inside blog_dir"
+            );
+            path
+        })
+        .collect();
+    if let Some(backup_dir) = &config.backup_dir {
+        std::fs::create_dir_all(backup_dir).unwrap();
+        config.backup_dir = Some(backup_dir.canonicalize().unwrap());
+    }
+    if let Some(preview_dir) = &config.preview_dir {
+        std::fs::create_dir_all(preview_dir).unwrap();
+        let preview_dir = preview_dir.canonicalize().unwrap();
+        assert!(
+            !preview_dir.starts_with(&config.blog_dir),
+            "preview_dir must be outside blog_dir"
+        );
+        config.preview_dir = Some(preview_dir);
+    }
+    if let Some(drafts_dir) = &config.drafts_dir {
+        std::fs::create_dir_all(drafts_dir).unwrap();
+        let drafts_dir = drafts_dir.canonicalize().unwrap();
+        assert!(
+            !drafts_dir.starts_with(&config.blog_dir),
+            "drafts_dir must be outside blog_dir"
+        );
+        config.drafts_dir = Some(drafts_dir);
+    }
+    for user in config.roles.keys() {
+        assert!(
+            config.users.contains_key(user),
+            "{} has a role but isn't in users",
+            user
+        );
+    }
+}
+
+/// security_headers plus content_security_policy, leaving out any set to an empty string.
+fn security_headers(config: &Config) -> HeaderMap {
+    let mut security_headers = HeaderMap::new();
+    for (name, value) in config.security_headers.iter().chain([(
+        &String::from("Content-Security-Policy"),
+        &config.content_security_policy,
+    )]) {
+        if value.is_empty() {
+            continue;
+        }
+        security_headers.insert(
+            HeaderName::try_from(name).unwrap(),
+            HeaderValue::try_from(value).unwrap(),
+        );
+    }
+    security_headers
+}
+
+/// Every route, with metrics, security headers, request ids and compression applied. main wraps
+/// it with the connection limit and timeouts.
+fn routes(
+    config: &'static Config,
+    state: &'static State,
+    tera: &'static RwLock<Tera>,
+) -> BoxedFilter<(Response<warp::hyper::Body>,)> {
+    let get_revert_diff = warp::get()
+        .and(warp::path!("revert" / "diff"))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_revert_diff(config, state, tera, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let get_revert = warp::get()
+        .and(warp::path("revert"))
+        .and_then(move || async move {
+            // moar keywords
+            match get_revert(config, state, tera).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let post_revert = warp::post()
+        .and(warp::path("revert"))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(warp::header::optional("authorization"))
+        .and_then(
+            move |form: HashMap<String, String>, authorization| async move {
+                match post_revert(config, state, form, authorization).await {
+                    Ok(ok) => Ok::<_, Rejection>(ok),
+                    Err(err) => Ok(err),
+                }
+            },
+        )
+        .recover(rate_limited);
+
+    let get_feed = warp::get()
+        .and(warp::path("feed.xml"))
+        .and_then(move || async move {
+            match get_feed(config, tera).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_search = warp::get()
+        .and(warp::path("search"))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_search(config, tera, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_edit_binary = warp::get()
+        .and(warp::path("edit"))
+        .and(query_flag("binary"))
+        .and(warp::path::full())
+        .and(warp::cookie::optional("editor_session"))
+        .and_then(move |path, session| async move {
+            match get_edit_binary(config, state, tera, path, session).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let get_edit = warp::get()
+        .and(warp::path("edit"))
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::cookie::optional("editor_session"))
+        .and(forwarded(config))
+        .and_then(move |path, headers, session, forwarded| async move {
+            match get_edit(config, state, tera, path, headers, session, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let get_edit_diff_rev = warp::get()
+        .and(warp::path!("edit" / "diff-rev"))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_edit_diff_rev(config, tera, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let post_restore = warp::post()
+        .and(warp::path("restore"))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(warp::cookie::optional("editor_session"))
+        .and(warp::header::headers_cloned())
+        .and_then(move |form, session, headers| async move {
+            match post_restore(config, state, form, session, headers).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited);
+
+    let post_check_links = warp::post()
+        .and(warp::path!("edit" / "check-links"))
+        .and(warp::filters::body::form())
+        .and_then(move |form: HashMap<String, String>| async move {
+            match post_check_links(config, form).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let post_edit_render = warp::post()
+        .and(warp::path!("edit" / "render"))
+        .and(warp::filters::body::form())
+        .and_then(move |form: HashMap<String, String>| async move {
+            match post_edit_render(config, form).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let post_edit_preview = warp::post()
+        .and(warp::path!("edit" / "preview"))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(forwarded(config))
+        .and(warp::header::optional("authorization"))
+        .and_then(
+            move |form: HashMap<String, String>, forwarded, authorization| async move {
+                match post_edit_preview(config, state, form, forwarded, authorization).await {
+                    Ok(ok) => Ok::<_, Rejection>(ok),
+                    Err(err) => Ok(err),
+                }
+            },
+        )
+        .recover(rate_limited);
+    let post_edit_autosave = warp::post()
+        .and(warp::path!("edit" / "autosave"))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(warp::cookie::optional("editor_session"))
+        .and(forwarded(config))
+        .and(warp::header::optional("authorization"))
+        .and_then(
+            move |form: HashMap<String, String>, session, forwarded, authorization| async move {
+                match post_edit_autosave(config, state, form, session, forwarded, authorization)
+                    .await
+                {
+                    Ok(ok) => Ok::<_, Rejection>(ok),
+                    Err(err) => Ok(err),
+                }
+            },
+        )
+        .recover(rate_limited);
+    let post_edit_unlock = warp::post()
+        .and(warp::path!("edit" / "unlock"))
+        .and(warp::filters::body::form())
+        .and(warp::cookie::optional("editor_session"))
+        .and(warp::header::optional("authorization"))
+        .and(forwarded(config))
+        .and_then(
+            move |form: HashMap<String, String>, session, authorization, forwarded| async move {
+                match post_edit_unlock(config, state, form, session, authorization, forwarded).await
+                {
+                    Ok(ok) => Ok::<_, Rejection>(ok),
+                    Err(err) => Ok(err),
+                }
+            },
+        );
+    let post_edit =
+        warp::post()
+            .and(warp::path("edit"))
+            .and(rate_limit(config, state))
+            .and(warp::path::full())
+            .and(warp::filters::body::form())
+            .and(warp::cookie::optional("editor_session"))
+            .and(forwarded(config))
+            .and(warp::header::headers_cloned())
+            .and_then(
+                move |path: FullPath,
+                      form: HashMap<String, String>,
+                      session,
+                      forwarded,
+                      headers| async move {
+                    match post_edit(config, state, path, form, session, forwarded, headers).await {
+                        Ok(ok) => Ok::<_, Rejection>(ok),
+                        Err(err) => Ok(err),
+                    }
+                },
+            )
+            .recover(rate_limited);
+
+    let get_publish = warp::get()
+        .and(warp::path(PUBLISH_ROUTE))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_publish(config, state, tera, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let put_edit = warp::put()
+        .and(warp::path("edit"))
+        .and(rate_limit(config, state))
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and(forwarded(config))
+        .and_then(move |path, headers, body, forwarded| async move {
+            match put_edit(config, state, path, headers, body, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited);
+
+    let post_publish = warp::post()
+        .and(warp::path(PUBLISH_ROUTE))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(warp::header::headers_cloned())
+        .and_then(move |form: HashMap<String, String>, headers| async move {
+            match post_publish(config, state, form, headers).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited);
+
+    let get_admin_status = warp::get()
+        .and(warp::path!("admin" / "status"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match get_admin_status(config, state, tera, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let get_admin_diff = warp::get()
+        .and(warp::path!("admin" / "diff"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match get_admin_diff(config, tera, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+    let post_admin_discard = warp::post()
+        .and(warp::path!("admin" / "discard"))
+        .and(warp::header::optional("authorization"))
+        .and(forwarded(config))
+        .and_then(move |authorization, forwarded| async move {
+            match post_admin_discard(config, state, authorization, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let post_admin_rebuild = warp::post()
+        .and(warp::path!("admin" / "rebuild"))
+        .and(rate_limit(config, state))
+        .and(warp::header::optional("authorization"))
+        .and(forwarded(config))
+        .and_then(move |authorization, forwarded| async move {
+            match post_admin_rebuild(config, state, authorization, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited);
+
+    let post_admin_promote = warp::post()
+        .and(warp::path!("admin" / "promote"))
+        .and(rate_limit(config, state))
+        .and(warp::filters::body::form())
+        .and(warp::header::optional("authorization"))
+        .and(forwarded(config))
+        .and_then(move |form, authorization, forwarded| async move {
+            match post_admin_promote(config, state, form, authorization, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited);
+
+    let post_admin_clear_degraded = warp::post()
+        .and(warp::path!("admin" / "clear-degraded"))
+        .and(warp::header::optional("authorization"))
+        .and(forwarded(config))
+        .and_then(move |authorization, forwarded| async move {
+            match post_admin_clear_degraded(config, state, authorization, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_admin_scheduled = warp::get()
+        .and(warp::path!("admin" / "scheduled"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match get_admin_scheduled(config, state, tera, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_admin_build_status = warp::get()
+        .and(warp::path!("admin" / "build-status"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match get_admin_build_status(config, state, tera, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let post_upload = warp::post()
+        .and(warp::path("upload"))
+        .and(rate_limit(config, state))
+        .and(warp::multipart::form().max_length(config.max_upload_bytes))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |form, authorization| async move {
+            match post_upload(config, state, form, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited)
+        .recover(upload_rejected);
+
+    let post_admin_reload_templates = warp::post()
+        .and(warp::path!("admin" / "reload-templates"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match post_admin_reload_templates(config, state, tera, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(forwarded(config))
+        .and_then(move |forwarded| async move {
+            match get_metrics(config, state, forwarded).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_health = warp::get()
+        .and(warp::path!("health"))
+        .and_then(move || async move {
+            match get_health(state).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_media = warp::get()
+        .and(warp::path("media"))
+        .and(enabled(config.media_dir.is_some()))
+        .and(serve_dir(config.media_dir.clone().unwrap_or_default()));
+
+    // what this editor last copied out, rather than what blog_url is serving
+    let get_preview_site = warp::get()
+        .and(warp::path("preview-site"))
+        .and(enabled(config.preview_dir.is_some()))
+        .and(serve_dir(config.preview_dir.clone().unwrap_or_default()));
+
+    let get_built = warp::get()
+        .and(warp::path("built"))
+        .and(serve_dir(config.dest_dir.clone()));
+
+    let get_api_content = warp::get()
+        .and(warp::path!("content"))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_api_content(config, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_api_content_at = warp::get()
+        .and(warp::path!("content-at"))
+        .and(warp::query())
+        .and(warp::header::headers_cloned())
+        .and_then(move |query, headers| async move {
+            match get_api_content_at(config, query, headers).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_api_revisions = warp::get()
+        .and(warp::path!("revisions"))
+        .and(warp::query())
+        .and_then(move |query| async move {
+            match get_api_revisions(config, query).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let get_api_whoami = warp::get()
+        .and(warp::path!("whoami"))
+        .and(warp::header::optional("authorization"))
+        .and_then(move |authorization| async move {
+            match get_api_whoami(config, authorization).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        });
+
+    let post_api_batch = warp::post()
+        .and(warp::path!("batch"))
+        .and(rate_limit(config, state))
+        .and(warp::body::json())
+        .and(warp::header::headers_cloned())
+        .and_then(move |edits, headers| async move {
+            match post_api_batch(config, state, edits, headers).await {
+                Ok(ok) => Ok::<_, Rejection>(ok),
+                Err(err) => Ok(err),
+            }
+        })
+        .recover(rate_limited)
+        .recover(json_rejected);
+
+    let api = warp::path("api")
+        .and(
+            get_api_content
+                .or(get_api_content_at)
+                .or(get_api_revisions)
+                .or(get_api_whoami)
+                .or(post_api_batch)
+                .or(warp::method()
+                    .and(warp::path::full())
+                    .map(move |method, path| no_route(config, &method, &path))),
+        )
+        .with(cors(config))
+        .boxed();
+
+    // boxed in groups, the whole table in one type is too deep for rustc
+    let edit_routes = get_edit_diff_rev
+        .or(get_edit_binary)
+        .or(get_edit)
+        .or(post_check_links)
+        .or(post_edit_render)
+        .or(post_edit_preview)
+        .or(post_edit_autosave)
+        .or(post_edit_unlock)
+        .or(post_edit)
+        .or(put_edit)
+        .or(get_publish)
+        .or(post_publish)
+        .or(post_upload)
+        .map(Reply::into_response)
+        .boxed();
+    let admin_routes = get_media
+        .or(get_built)
+        .or(get_preview_site)
+        .or(get_admin_status)
+        .or(get_admin_diff)
+        .or(post_admin_discard)
+        .or(post_admin_rebuild)
+        .or(post_admin_promote)
+        .or(post_admin_clear_degraded)
+        .or(get_admin_scheduled)
+        .or(get_admin_build_status)
+        .or(post_admin_reload_templates)
+        .or(get_metrics)
+        .or(get_health)
+        .map(Reply::into_response)
+        .boxed();
+    let route = mount(&config.mount_prefix)
+        .and(
+            api.or(get_revert_diff)
+                .or(get_revert)
+                .or(post_revert)
+                .or(post_restore)
+                .or(get_feed)
+                .or(get_search)
+                .or(edit_routes)
+                .or(admin_routes),
+        )
+        .recover(form_rejected)
+        .or(warp::method()
+            .and(warp::path::full())
+            .map(move |method, path| not_found_page(config, tera, &method, &path)))
+        .map(Reply::into_response)
+        .boxed();
+
+    let security_headers: &'static _ = Box::leak(Box::new(security_headers(config)));
+
+    let route = warp::method().and(warp::path::full()).and(route).map(
+        move |method: Method, path: FullPath, reply| {
+            let response = Reply::into_response(reply);
+            METRICS.request(
+                method.to_string(),
+                metrics_route(config, path.as_str()),
+                response.status(),
+            );
+            response
+        },
+    );
+
+    let route = route.map(move |mut response: Response<warp::hyper::Body>| {
+        for (name, value) in security_headers {
+            if !response.headers().contains_key(name) {
+                response.headers_mut().insert(name, value.clone());
+            }
+        }
+        response
+    });
+
+    let route = warp::ext::optional::<RequestId>()
+        .and(route)
+        .then(tag_request_id);
+
+    let route = warp::header::optional::<String>("accept-encoding")
+        .and(route)
+        .then(move |accept_encoding, response| compress(config, accept_encoding, response));
+
+    route.boxed()
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        std::process::exit(2);
+    });
+
+    let config_buf = std::fs::read_to_string(&args.config).unwrap_or_else(|err| {
+        eprintln!("couldn't read {}: {}", args.config.display(), err);
+        std::process::exit(1);
+    });
+    let mut config_table = parse_config_table(&args.config, &config_buf).unwrap_or_else(|err| {
+        eprintln!("couldn't parse {}: {}", args.config.display(), err);
+        std::process::exit(1);
+    });
+    env_overrides(&mut config_table, std::env::vars());
+    let mut config: Config = config_table.try_into().unwrap();
+
+    if args.bind.is_some() || args.port.is_some() {
+        let bind = args.bind.or(config.bind);
+        config.bind = Some(match args.port {
+            Some(port) => SocketAddr::new(
+                bind.map(|bind| bind.ip())
+                    .unwrap_or(Ipv4Addr::LOCALHOST.into()),
+                port,
+            ),
+            None => bind.unwrap(),
+        });
+        config.bind_unix = None;
+    }
+    resolve_config(&mut config);
+    if args.selftest {
+        match selftest(&config).await {
+            Ok(()) => {
+                println!("self-test passed");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("self-test failed: {}", err.body());
+                std::process::exit(1);
+            }
+        }
+    }
+    let listener = bind_listener(&config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    // an empty lock_file turns the check off
+    let lock_file =
+        (!config.lock_file.as_os_str().is_empty()).then(|| config.blog_dir.join(&config.lock_file));
+    // the file stays behind at exit, deleting it would let a new instance lock a fresh file while
+    // another still has the old one. exiting drops the lock
+    let _lock = lock_file.as_ref().map(|lock_file| {
+        acquire_lock_file(lock_file).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    if let Some(preview_dir) = &config.preview_dir {
+        // previews only last until preview_ttl_secs, anything left from before is stale. not in
+        // resolve_config, before lock_file is held they could be another instance's
+        let _ = std::fs::remove_dir_all(preview_dir);
+        std::fs::create_dir_all(preview_dir).unwrap();
+    }
+    let config: &'static Config = Box::leak(Box::new(config));
+    let state: &'static State = Box::leak(Box::new(State::new(config)));
+
+    if let Some(schedule_file) = &config.schedule_file {
+        if schedule_file.exists() {
+            // starting empty would overwrite the file with the next save
+            let schedule: Vec<ScheduledPost> = std::fs::read_to_string(schedule_file)
+                .map_err(|err| err.to_string())
+                .and_then(|schedule| serde_json::from_str(&schedule).map_err(|err| err.to_string()))
+                .unwrap_or_else(|err| {
+                    eprintln!("couldn't read {}: {}", schedule_file.display(), err);
+                    std::process::exit(1);
+                });
+            for post in &schedule {
+                tokio::spawn(publish_scheduled(config, state, post.clone()));
+            }
+            *state.schedule.lock().await = schedule;
+        }
+    }
+
+    if let Some(prune_interval_secs) = config.prune_interval_secs {
+        tokio::spawn(prune_periodically(
+            config,
+            state,
+            Duration::from_secs(prune_interval_secs),
+        ));
+    }
+
+    let _watcher = config.watch_blog_dir.then(|| {
+        watch_blog_dir(config, state).unwrap_or_else(|err| {
+            eprintln!("couldn't watch {}: {}", config.blog_dir.display(), err);
+            std::process::exit(1);
+        })
+    });
+
+    let tera: &'static _ = Box::leak(Box::new(RwLock::new(load_templates(config).unwrap())));
+
+    let security_headers: &'static _ = Box::leak(Box::new(security_headers(config)));
+    let service = warp::service(routes(config, state, tera));
+    let unavailable = move |method: String, path: &str, request_id: &RequestId, body: &str| {
+        let mut response = Reply::into_response(response_with_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{}{}", body, request_id.suffix()),
+        ));
+        METRICS.request(method, metrics_route(config, path), response.status());
+        response.headers_mut().extend(security_headers.clone());
+        if let Ok(value) = HeaderValue::try_from(&request_id.0) {
+            response.headers_mut().insert("x-request-id", value);
+        }
+        response
+    };
+
+    // bounds how many requests are handled at once, unlike the rate limiter which bounds how often
+    // each client may write. /metrics is exempt so the server can still be watched when saturated
+    let in_flight = Arc::new(Semaphore::new(match config.max_concurrent_requests {
+        0 => Semaphore::MAX_PERMITS,
+        max_concurrent_requests => max_concurrent_requests,
+    }));
+
+    let handle = move |remote: Option<SocketAddr>, mut request: Request<warp::hyper::Body>| {
+        let mut service = service.clone();
+        let in_flight = in_flight.clone();
+        async move {
+            if let Some(remote) = remote {
+                request.extensions_mut().insert(RemoteAddr(remote));
+            }
+
+            if config.normalize_routes {
+                if let Some(path) = normalize_route(config, request.uri().path()) {
+                    let path_and_query = match request.uri().query() {
+                        Some(query) => format!("{}?{}", path, query),
+                        None => path,
+                    };
+                    let mut parts = request.uri().clone().into_parts();
+                    parts.path_and_query = path_and_query.parse().ok();
+                    if let Ok(uri) = Uri::from_parts(parts) {
+                        *request.uri_mut() = uri;
+                    }
+                }
+            }
+
+            let method = request.method().to_string();
+            let path = request.uri().path().to_string();
+            let request_id = RequestId::new(request.headers());
+            request.extensions_mut().insert(request_id.clone());
+
+            let _permit = match in_flight.try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) if metrics_route(config, &path) == "metrics" => None,
+                Err(_) => {
+                    let mut response =
+                        unavailable(method, &path, &request_id, "too many requests in flight");
+                    response
+                        .headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                    return Ok(response);
+                }
+            };
+
+            // dropping a write halfway could leave a file written but not committed, or dest_dir
+            // removed but not copied back, so writes get their own task that runs to the end and
+            // the timeout only stops waiting for it
+            let write = !matches!(
+                *request.method(),
+                Method::GET | Method::HEAD | Method::OPTIONS
+            );
+            let timeout = Duration::from_secs(config.request_timeout_secs);
+            let response = if write {
+                let call = tokio::spawn(service.call(request));
+                tokio::time::timeout(timeout, call).await.map(|joined| {
+                    joined.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+                })
+            } else {
+                tokio::time::timeout(timeout, service.call(request)).await
+            };
+            match response {
+                Ok(response) => {
+                    if let Ok(response) = &response {
+                        if response.status().is_server_error() {
+                            println!(
+                                "WARNING: {} {} returned {} (request id: {})",
+                                method,
+                                path,
+                                response.status(),
+                                request_id.0
+                            );
+                        }
+                    }
+                    response
+                }
+                Err(_) => {
+                    eprintln!(
+                        "request timed out after {:?}: {} {} (request id: {})",
+                        timeout, method, path, request_id.0
+                    );
+                    let body = if write {
+                        "request timed out, the change is still being made in the background"
+                    } else {
+                        "request timed out"
+                    };
+                    Ok(unavailable(method, &path, &request_id, body))
+                }
+            }
+        }
+    };
+
+    let header_read_timeout = Duration::from_secs(config.header_read_timeout_secs);
+    let result = match listener {
+        Listener::Tcp(listener) => {
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let remote = conn.remote_addr();
+                let handle = handle.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| handle(Some(remote), request)))
+                }
+            });
+
+            match warp::hyper::Server::from_tcp(listener) {
+                Ok(server) => {
+                    server
+                        .tcp_keepalive(config.tcp_keepalive_secs.map(Duration::from_secs))
+                        .http1_header_read_timeout(header_read_timeout)
+                        .serve(make_service)
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let make_service = make_service_fn(move |_| {
+                let handle = handle.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |request| handle(None, request))) }
+            });
+
+            warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(
+                tokio_stream::wrappers::UnixListenerStream::new(listener),
+            ))
+            .http1_header_read_timeout(header_read_timeout)
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("server error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A blog_dir that's a git repository with a.md committed, and a stand-in blog at blog_url
+    /// whose pages name their source file as `source: <path>`. /missing 404s and /broken 500s.
+    struct Blog {
+        root: PathBuf,
+        config: &'static Config,
+        state: &'static State,
+        tera: &'static RwLock<Tera>,
+    }
+
+    impl Drop for Blog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// extra is appended to the config, replacing any key it sets, so top-level keys go before any
+    /// tables.
+    async fn blog(extra: &str) -> Blog {
+        // temp_dir is a symlink on some platforms, and blog_dir gets canonicalized
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("editor-test-{}", random_token()));
+        let blog_dir = root.join("blog");
+        std::fs::create_dir_all(blog_dir.join("public")).unwrap();
+        std::fs::create_dir_all(root.join("dest")).unwrap();
+        std::fs::write(blog_dir.join(".gitignore"), "public/\n").unwrap();
+        std::fs::write(blog_dir.join("a.md"), "hello\n").unwrap();
+        git(&blog_dir, &["init", "-q"]);
+        git(&blog_dir, &["config", "user.name", "editor"]);
+        git(&blog_dir, &["config", "user.email", "editor@example.com"]);
+        git(&blog_dir, &["add", "-A"]);
+        git(&blog_dir, &["commit", "-q", "-m", "init"]);
+
+        let base = format!(
+            r#"
+url = "http://editor.example.com/"
+blog_url = "{}"
+path_regex = 'source: (\S+)'
+blog_dir = "{}"
+blog_build_dir = "{}"
+dest_dir = "{}"
+blog_fetch_attempts = 1
+build_command = ["sh", "-c", "echo built > public/index.html"]
+create_revision = ["git", "commit", "-q", "-m"]
+stage_revision = ["git", "add", "--"]
+reset_command = ["sh", "-c", "git reset -q --hard && git clean -qfd"]
+revert_revision = ["git", "revert", "--no-edit"]
+copy_command = ["cp", "-r"]
+"#,
+            serve_blog().await,
+            blog_dir.display(),
+            blog_dir.join("public").display(),
+            root.join("dest").display(),
+        );
+        let key = |line: &str| line.split_once(" = ").map(|(key, _)| key.to_string());
+        let overridden = extra.lines().filter_map(key).collect::<HashSet<_>>();
+        let config = base
+            .lines()
+            .filter(|line| key(line).is_none_or(|key| !overridden.contains(&key)))
+            .chain(extra.lines())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut config: Config = toml::from_str(&config).unwrap();
+        resolve_config(&mut config);
+        let config: &'static Config = Box::leak(Box::new(config));
+
+        Blog {
+            root,
+            state: Box::leak(Box::new(State::new(config))),
+            tera: Box::leak(Box::new(RwLock::new(load_templates(config).unwrap()))),
+            config,
+        }
+    }
+
+    async fn serve_blog() -> Url {
+        let pages = warp::path::full().map(|path: FullPath| match path.as_str() {
+            "/missing" => response_with_status(StatusCode::NOT_FOUND, "not found"),
+            "/broken" => five_hundred("broken"),
+            path => Response::new(format!("source: {}", path.trim_start_matches('/'))),
+        });
+        let (addr, server) = warp::serve(pages).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    impl Blog {
+        async fn reply(
+            &self,
+            request: warp::test::RequestBuilder,
+        ) -> Response<warp::hyper::body::Bytes> {
+            request
+                .reply(&routes(self.config, self.state, self.tera))
+                .await
+        }
+
+        fn path(&self, relative_path: &str) -> PathBuf {
+            self.config.blog_dir.join(relative_path)
+        }
+
+        fn read(&self, relative_path: &str) -> String {
+            std::fs::read_to_string(self.path(relative_path)).unwrap()
+        }
+    }
+
+    fn body(response: &Response<warp::hyper::body::Bytes>) -> String {
+        String::from_utf8_lossy(response.body()).into_owned()
+    }
+
+    fn post_form(path: &str, pairs: &[(&str, &str)]) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(
+                url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(pairs)
+                    .finish(),
+            )
+    }
+
+    fn basic_auth(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+    }
+
+    #[tokio::test]
+    async fn cors_allows_configured_origins_on_the_api() {
+        let blog = blog(r#"cors_allowed_origins = ["https://admin.example.com"]"#).await;
+
+        let allowed = blog
+            .reply(
+                warp::test::request()
+                    .path("/api/content?path=a.md")
+                    .header("origin", "https://admin.example.com"),
+            )
+            .await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(
+            allowed.headers()["access-control-allow-origin"],
+            "https://admin.example.com"
+        );
+
+        let other = blog
+            .reply(
+                warp::test::request()
+                    .path("/api/content?path=a.md")
+                    .header("origin", "https://evil.example.com"),
+            )
+            .await;
+        assert!(!other.headers().contains_key("access-control-allow-origin"));
+
+        // the HTML routes are same-origin only
+        let html = blog
+            .reply(
+                warp::test::request()
+                    .path("/search?q=hello")
+                    .header("origin", "https://admin.example.com"),
+            )
+            .await;
+        assert!(!html.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn failed_delete_is_reset() {
+        let blog = blog(
+            r#"create_revision = ["false"]
+status_command = ["git", "status", "--porcelain"]"#,
+        )
+        .await;
+
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("delete", "on"), ("content", "")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("had to reset"));
+        assert!(!body(&response).contains("WARNING"));
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn failed_delete_warns_when_reset_leaves_it_deleted() {
+        // only resets the index
+        let blog = blog(
+            r#"create_revision = ["false"]
+reset_command = ["git", "reset", "-q"]"#,
+        )
+        .await;
+
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("delete", "on"), ("content", "")],
+            ))
+            .await;
+        assert!(body(&response).contains("WARNING: reset did NOT restore deleted file"));
+        assert!(!blog.path("a.md").exists());
+    }
+
+    #[tokio::test]
+    async fn get_edit_answers_conditional_requests_with_304() {
+        let blog = blog("").await;
+
+        let first = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[ETAG].to_str().unwrap().to_string();
+        let last_modified = first.headers()[LAST_MODIFIED].to_str().unwrap().to_string();
+        let session = first.headers()[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let get_edit = |name, value: &str| {
+            warp::test::request()
+                .path("/edit/a.md")
+                .header("cookie", &session)
+                .header(name, value)
+        };
+        let response = blog.reply(get_edit(IF_NONE_MATCH, &etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+        let response = blog
+            .reply(get_edit(IF_MODIFIED_SINCE, &last_modified))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // another session sees the lock banner, so it gets the page
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .path("/edit/a.md")
+                    .header(IF_NONE_MATCH, &etag),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        std::fs::write(blog.path("a.md"), "changed\n").unwrap();
+        let response = blog.reply(get_edit(IF_NONE_MATCH, &etag)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn edits_are_written_with_lf_line_endings_when_asked() {
+        let untouched = blog("").await;
+        let response = untouched
+            .reply(post_form("/edit/a.md", &[("content", "one\r\ntwo  \r\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(untouched.read("a.md"), "one\r\ntwo  \r\n");
+
+        let blog = blog("normalize_line_endings = true").await;
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "one\r\ntwo  \r\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "one\ntwo  \n");
+    }
+
+    #[tokio::test]
+    async fn edits_can_strip_trailing_whitespace_and_newlines() {
+        let blog = blog(
+            r#"normalize_line_endings = true
+strip_trailing_whitespace = true
+ensure_trailing_newline = true"#,
+        )
+        .await;
+
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("content", "one \r\ntwo\t\r\n\r\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "one\ntwo\n");
+    }
+
+    #[tokio::test]
+    async fn simultaneous_publishes_of_the_same_name_write_once() {
+        let blog = blog("").await;
+
+        let publish =
+            |content| post_form("/publish", &[("filename", "b.md"), ("content", content)]);
+        let (first, second) = tokio::join!(
+            blog.reply(publish("first\n")),
+            blog.reply(publish("second\n")),
+        );
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::BAD_REQUEST]);
+        let (winner, loser) = if first.status().is_success() {
+            ("first\n", second)
+        } else {
+            ("second\n", first)
+        };
+        assert!(body(&loser).contains("already exists"));
+        assert_eq!(blog.read("b.md"), winner);
+    }
+
+    #[tokio::test]
+    async fn only_editable_extensions_can_be_edited_or_published() {
+        let blog = blog("").await;
+        std::fs::write(blog.path("config.toml"), "secret = true\n").unwrap();
+
+        let allowed = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        let disallowed = blog
+            .reply(warp::test::request().path("/edit/config.toml"))
+            .await;
+        assert_eq!(disallowed.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&disallowed).contains("can't edit .toml files"));
+
+        let allowed = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "b.md"), ("content", "b\n")],
+            ))
+            .await;
+        assert_eq!(allowed.status(), StatusCode::OK, "{}", body(&allowed));
+        let disallowed = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "run.sh"), ("content", "rm -rf /\n")],
+            ))
+            .await;
+        assert_eq!(disallowed.status(), StatusCode::BAD_REQUEST);
+        assert!(!blog.path("run.sh").exists());
+    }
+
+    #[tokio::test]
+    async fn missing_blog_pages_redirect_to_publish_and_broken_ones_are_502() {
+        let blog = blog("").await;
+
+        let missing = blog
+            .reply(warp::test::request().path("/edit/missing"))
+            .await;
+        assert!(body(&missing).contains("URL=http://editor.example.com/publish/missing"));
+
+        let broken = blog.reply(warp::test::request().path("/edit/broken")).await;
+        assert_eq!(broken.status(), StatusCode::BAD_GATEWAY);
+        assert!(body(&broken).contains("500 Internal Server Error"));
+    }
+
+    #[tokio::test]
+    async fn routes_are_mounted_under_mount_prefix() {
+        let blog = blog(r#"mount_prefix = "/admin/""#).await;
+
+        let edit = blog
+            .reply(warp::test::request().path("/admin/edit/a.md"))
+            .await;
+        assert_eq!(edit.status(), StatusCode::OK);
+        assert!(body(&edit).contains(r#"action="/admin/edit/diff-rev""#));
+        assert!(body(&edit).contains(r#"fetch("/admin/edit/render""#));
+
+        let unmounted = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert_eq!(unmounted.status(), StatusCode::NOT_FOUND);
+
+        let response = blog
+            .reply(post_form("/admin/edit/a.md", &[("content", "mounted\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "mounted\n");
+    }
+
+    #[tokio::test]
+    async fn failed_validation_creates_no_commit() {
+        let blog = blog(r#"validate_command = ["false"]"#).await;
+        let revisions = git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]);
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "invalid\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("validation"));
+        assert_eq!(
+            git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]),
+            revisions
+        );
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn content_over_max_content_bytes_is_not_written() {
+        let blog = blog("max_content_bytes = 8").await;
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "much too long\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn publish_slugifies_the_filename() {
+        let blog = blog("slugify_filename = true").await;
+
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[
+                    ("filename", "  My Messy_Post!! 2.MD"),
+                    ("content", "messy\n"),
+                ],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(body(&response).contains("to my-messypost-2.md"));
+        assert_eq!(blog.read("my-messypost-2.md"), "messy\n");
+
+        // still collides with what's there
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "A.md"), ("content", "again\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("already exists"));
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn missing_source_files_are_404_and_other_io_errors_500() {
+        let blog = blog("").await;
+        let put = |path| {
+            warp::test::request()
+                .method("PUT")
+                .path(path)
+                .header(CONTENT_TYPE, "text/plain")
+                .body("content\n")
+        };
+
+        let missing = blog.reply(put("/edit/gone.md")).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert!(body(&missing).contains("gone.md, which doesn't exist"));
+
+        // stands in for permission denied, which root never gets
+        let not_a_directory = blog.reply(put("/edit/a.md/b.md")).await;
+        assert_eq!(not_a_directory.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn binary_puts_need_put_content_types() {
+        let put = |content_type| {
+            warp::test::request()
+                .method("PUT")
+                .path("/edit/favicon.ico")
+                .header(CONTENT_TYPE, content_type)
+                .body(vec![0u8, 159, 146, 150])
+        };
+
+        let refused = blog(r#"binary_extensions = ["ico"]"#).await;
+        std::fs::write(refused.path("favicon.ico"), b"old").unwrap();
+        let response = refused.reply(put("application/octet-stream")).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(refused.read("favicon.ico"), "old");
+
+        let blog = blog(
+            r#"binary_extensions = ["ico"]
+put_content_types = ["application/octet-stream"]"#,
+        )
+        .await;
+        std::fs::write(blog.path("favicon.ico"), b"old").unwrap();
+        let response = blog.reply(put("application/octet-stream")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(
+            std::fs::read(blog.path("favicon.ico")).unwrap(),
+            [0u8, 159, 146, 150]
+        );
+        assert_eq!(git(&blog.config.blog_dir, &["status", "--porcelain"]), "");
+
+        // only to binary_extensions files, a.md isn't one
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .method("PUT")
+                    .path("/edit/a.md")
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body("edited\n"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn revert_tokens_are_used_up_only_by_a_revert_that_goes_through() {
+        let blog = blog("").await;
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        let revision = git(&blog.config.blog_dir, &["rev-parse", "HEAD"]);
+        let revision = revision.trim();
+        let token = blog.state.revert_token(blog.config, revision);
+        let revert =
+            |token: &str| post_form("/revert", &[("revision", revision), ("token", token)]);
+
+        let mut tampered = token.clone();
+        let last = if tampered.pop() == Some('0') {
+            '1'
+        } else {
+            '0'
+        };
+        tampered.push(last);
+        let response = blog.reply(revert(&tampered)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("revert token isn't for"));
+        let other = blog.state.revert_token(blog.config, "0123456789abcdef");
+        let response = blog.reply(revert(&other)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // git revert refuses to overwrite local changes
+        std::fs::write(blog.path("a.md"), "local\n").unwrap();
+        let response = blog.reply(revert(&token)).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        git(&blog.config.blog_dir, &["checkout", "HEAD", "--", "a.md"]);
+
+        let response = blog.reply(revert(&token)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let response = blog.reply(revert(&token)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("already used"));
+    }
+
+    #[tokio::test]
+    async fn restoring_lets_go_of_the_edit_lock_and_cached_path() {
+        let blog = blog("").await;
+        let original = git(&blog.config.blog_dir, &["rev-parse", "HEAD"]);
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .path("/edit/a.md")
+                    .header("cookie", "editor_session=mine"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let actual_path = blog.path("a.md");
+        assert!(blog.state.is_locked(&actual_path));
+        assert!(blog.state.path_cache.lock().unwrap().contains_key("/a.md"));
+
+        let response = blog
+            .reply(
+                post_form(
+                    "/restore",
+                    &[("path", "a.md"), ("revision", original.trim())],
+                )
+                .header("cookie", "editor_session=mine"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "hello\n");
+        assert!(!blog.state.is_locked(&actual_path));
+        assert!(blog.state.path_cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches_let_go_of_the_edit_locks_and_cached_paths() {
+        let blog = blog("").await;
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .path("/edit/a.md")
+                    .header("cookie", "editor_session=mine"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let actual_path = blog.path("a.md");
+        assert!(blog.state.is_locked(&actual_path));
+        assert!(blog.state.path_cache.lock().unwrap().contains_key("/a.md"));
+
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .method("POST")
+                    .path("/api/batch")
+                    .json(&serde_json::json!([
+                        { "path": "a.md", "content": "edited\n" },
+                        { "path": "new.md", "content": "new\n" },
+                    ])),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "edited\n");
+        assert_eq!(blog.read("new.md"), "new\n");
+        assert!(!blog.state.is_locked(&actual_path));
+        assert!(blog.state.path_cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scheduled_posts_are_published_when_their_time_comes() {
+        let schedule_file =
+            std::env::temp_dir().join(format!("editor-test-schedule-{}.json", random_token()));
+        let blog = blog(&format!("schedule_file = \"{}\"", schedule_file.display())).await;
+        let publish_at = (Utc::now() + Duration::from_secs(2)).to_rfc3339();
+        let publish = || {
+            post_form(
+                "/publish",
+                &[
+                    ("filename", "b.md"),
+                    ("content", "later\n"),
+                    ("publish_at", publish_at.as_str()),
+                ],
+            )
+        };
+
+        let response = blog.reply(publish()).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(body(&response).contains("scheduled"));
+        assert!(!blog.path("b.md").exists());
+        assert!(std::fs::read_to_string(&schedule_file)
+            .unwrap()
+            .contains("later"));
+
+        let response = blog.reply(publish()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("already scheduled"));
+
+        let started = Instant::now();
+        while std::fs::read_to_string(&schedule_file)
+            .unwrap()
+            .contains("later")
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(20),
+                "never published"
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        std::fs::remove_file(&schedule_file).unwrap();
+        assert_eq!(blog.read("b.md"), "later\n");
+        assert_eq!(
+            git(
+                &blog.config.blog_dir,
+                &["log", "-1", "--name-only", "--format="]
+            )
+            .trim(),
+            "b.md"
+        );
+    }
+
+    fn upload(filename: &str, data: &[u8]) -> warp::test::RequestBuilder {
+        let mut form = format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            filename
+        )
+        .into_bytes();
+        form.extend_from_slice(data);
+        form.extend_from_slice(b"\r\n--boundary--\r\n");
+        warp::test::request()
+            .method("POST")
+            .path("/upload")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(form)
+    }
+
+    #[tokio::test]
+    async fn uploads_are_limited_in_size_and_to_unprotected_paths() {
+        let media = blog(
+            r#"media_dir = "media"
+max_upload_bytes = 512
+protected_paths = ["media/logo.png"]"#,
+        )
+        .await;
+
+        let response = media.reply(upload("cat.png", b"\x89PNG")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(
+            std::fs::read(media.path("media/cat.png")).unwrap(),
+            b"\x89PNG"
+        );
+        assert_eq!(
+            git(
+                &media.config.blog_dir,
+                &["log", "-1", "--name-only", "--format="]
+            )
+            .trim(),
+            "media/cat.png"
+        );
+
+        let response = media.reply(upload("cat.png", b"again")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&response).contains("already exists"));
+
+        let response = media.reply(upload("big.png", &[0; 1024])).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!media.path("media/big.png").exists());
+
+        let response = media.reply(upload("logo.png", b"\x89PNG")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body(&response).contains("is protected"));
+        assert!(!media.path("media/logo.png").exists());
+
+        let response = media.reply(upload("cat.exe", b"MZ")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!media.path("media/cat.exe").exists());
+
+        let prefixed = blog(
+            r#"media_dir = "media"
+editable_path_prefixes = ["content"]"#,
+        )
+        .await;
+        let response = prefixed.reply(upload("cat.png", b"\x89PNG")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body(&response).contains("isn't in an editable directory"));
+        assert!(!prefixed.path("media/cat.png").exists());
+    }
+
+    #[tokio::test]
+    async fn writes_past_the_burst_are_rate_limited() {
+        let blog = blog(
+            r#"rate_limit_per_minute = 1
+rate_limit_burst = 2"#,
+        )
+        .await;
+        let edit = |content| post_form("/edit/a.md", &[("content", content)]);
+
+        for content in ["one\n", "two\n"] {
+            let response = blog.reply(edit(content)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        }
+        let response = blog.reply(edit("three\n")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{}", retry_after);
+        assert_eq!(blog.read("a.md"), "two\n");
+
+        // reads aren't limited
+        let response = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_delivered_for_webhook_actions() {
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let hook = warp::post()
+            .and(warp::body::json())
+            .map(move |webhook: serde_json::Value| {
+                sender.send(webhook).unwrap();
+                StatusCode::NO_CONTENT
+            });
+        let (addr, server) = warp::serve(hook).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let blog = blog(&format!(
+            "webhook_url = \"http://{}/hook\"\nwebhook_actions = [\"edit\"]",
+            addr
+        ))
+        .await;
+
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "b.md"), ("content", "new\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("content", "edited\n"), ("note", "typo")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+
+        let webhook = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("no webhook")
+            .unwrap();
+        assert_eq!(webhook["action"], "edit");
+        assert_eq!(webhook["path"], "a.md");
+        assert_eq!(webhook["note"], "typo");
+        assert_eq!(
+            webhook["commit"],
+            git(&blog.config.blog_dir, &["rev-parse", "--short", "HEAD"]).trim()
+        );
+        // publish isn't in webhook_actions
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file() {
+        let mut config: toml::Table = toml::from_str(
+            r#"
+bind = "127.0.0.1:2222"
+url = "http://editor.example.com/"
+compression = true
+"#,
+        )
+        .unwrap();
+
+        env_overrides(
+            &mut config,
+            [
+                ("EDITOR_BIND", "0.0.0.0:2222"),
+                ("EDITOR_COMPRESSION", "false"),
+                ("EDITOR_USERS", r#"{ me = "hunter2" }"#),
+                ("PATH", "/usr/bin"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+
+        assert_eq!(config["bind"].as_str(), Some("0.0.0.0:2222"));
+        assert_eq!(config["compression"].as_bool(), Some(false));
+        assert_eq!(config["users"]["me"].as_str(), Some("hunter2"));
+        // the file's value stays where there's no variable for it
+        assert_eq!(config["url"].as_str(), Some("http://editor.example.com/"));
+        assert!(!config.contains_key("path"));
+    }
+
+    #[test]
+    fn toml_yaml_and_json_configs_are_equivalent() {
+        let toml = r#"
+url = "http://editor.example.com/"
+blog_url = "http://blog.example.com/"
+path_regex = ['source: (\S+)', 'src: (\S+)']
+blog_dir = "blog"
+blog_build_dir = "blog/public"
+dest_dir = "dest"
+build_command = ["make", "-C", "blog"]
+create_revision = ["git", "commit", "-m"]
+stage_revision = ["git", "add", "--"]
+reset_command = ["git", "reset", "--hard"]
+revert_revision = ["git", "revert", "--no-edit"]
+copy_command = ["cp", "-r"]
+compression = false
+"#;
+        let yaml = r#"
+url: http://editor.example.com/
+blog_url: http://blog.example.com/
+path_regex:
+  - 'source: (\S+)'
+  - 'src: (\S+)'
+blog_dir: blog
+blog_build_dir: blog/public
+dest_dir: dest
+build_command: [make, -C, blog]
+create_revision:
+  - git
+  - commit
+  - -m
+stage_revision: [git, add, --]
+reset_command: [git, reset, --hard]
+revert_revision: [git, revert, --no-edit]
+copy_command: [cp, -r]
+compression: false
+bind: null
+"#;
+        let json = r#"{
+            "url": "http://editor.example.com/",
+            "blog_url": "http://blog.example.com/",
+            "path_regex": ["source: (\\S+)", "src: (\\S+)"],
+            "blog_dir": "blog",
+            "blog_build_dir": "blog/public",
+            "dest_dir": "dest",
+            "build_command": ["make", "-C", "blog"],
+            "create_revision": ["git", "commit", "-m"],
+            "stage_revision": ["git", "add", "--"],
+            "reset_command": ["git", "reset", "--hard"],
+            "revert_revision": ["git", "revert", "--no-edit"],
+            "copy_command": ["cp", "-r"],
+            "compression": false,
+            "bind": null
+        }"#;
+
+        let toml = parse_config_table(Path::new("editor.toml"), toml).unwrap();
+        for (path, config) in [
+            ("editor.yaml", yaml),
+            ("editor.yml", yaml),
+            ("editor.json", json),
+        ] {
+            assert_eq!(parse_config_table(Path::new(path), config).unwrap(), toml);
+        }
+        // extensionless is TOML
+        assert!(parse_config_table(Path::new("editor"), yaml).is_err());
+
+        let config: Config = toml.try_into().unwrap();
+        assert_eq!(config.path_regex.len(), 2);
+        assert_eq!(config.build_command, ["make", "-C", "blog"]);
+    }
+
+    #[tokio::test]
+    async fn textarea_content_survives_a_round_trip() {
+        let blog = blog("").await;
+        let content = "<p>close it</p>\n</textarea><script>alert(1)</script>\n&amp; \"quoted\"\n";
+        std::fs::write(blog.path("page.html"), content).unwrap();
+
+        let edit = blog
+            .reply(warp::test::request().path("/edit/page.html"))
+            .await;
+        assert_eq!(edit.status(), StatusCode::OK);
+        let page = body(&edit);
+        assert!(page.contains(r#"data-mode="html""#));
+        assert!(!page.contains("<script>alert(1)</script>"));
+
+        // what a browser would submit
+        let textarea = |id: &str| {
+            let start = page.find(&format!(r#"<textarea id="{}""#, id)).unwrap();
+            let start = start + page[start..].find('>').unwrap() + 1;
+            let end = start + page[start..].find("</textarea>").unwrap();
+            page[start..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&#x27;", "'")
+                .replace("&#x2F;", "/")
+                .replace("&amp;", "&")
+        };
+        let response = blog
+            .reply(post_form(
+                "/edit/page.html",
+                &[
+                    ("front_matter", &textarea("front_matter")),
+                    ("body", &textarea("textarea")),
+                ],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("page.html"), content);
+    }
+
+    #[tokio::test]
+    async fn protected_paths_cant_be_edited_deleted_or_published() {
+        let blog = blog(r#"protected_paths = ["index.md", "data/**"]"#).await;
+        std::fs::write(blog.path("index.md"), "home\n").unwrap();
+        git(&blog.config.blog_dir, &["add", "index.md"]);
+        git(&blog.config.blog_dir, &["commit", "-q", "-m", "index"]);
+
+        let edit = blog
+            .reply(post_form("/edit/index.md", &[("content", "defaced\n")]))
+            .await;
+        assert_eq!(edit.status(), StatusCode::FORBIDDEN);
+        let delete = blog
+            .reply(post_form(
+                "/edit/index.md",
+                &[("delete", "on"), ("content", "")],
+            ))
+            .await;
+        assert_eq!(delete.status(), StatusCode::FORBIDDEN);
+        assert_eq!(blog.read("index.md"), "home\n");
+
+        let publish = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "data/b.md"), ("content", "b\n")],
+            ))
+            .await;
+        assert_eq!(publish.status(), StatusCode::FORBIDDEN);
+        assert!(body(&publish).contains("data/b.md is protected"));
+
+        let unprotected = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(unprotected.status(), StatusCode::OK);
+        assert_eq!(blog.read("a.md"), "edited\n");
+    }
+
+    #[tokio::test]
+    async fn dry_run_publish_writes_nothing() {
+        let blog = blog("").await;
+        let revisions = git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]);
+
+        let response = blog
+            .reply(
+                post_form(
+                    "/publish",
+                    &[("filename", "b.md"), ("content", "b\n"), ("dry_run", "on")],
+                )
+                .header(ACCEPT, "application/json"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let dry_run: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(dry_run["relative_path"], "b.md");
+        assert_eq!(dry_run["exists"], false);
+        assert!(!blog.path("b.md").exists());
+
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "a.md"), ("content", "a\n"), ("dry_run", "on")],
+            ))
+            .await;
+        assert!(body(&response).ends_with("a.md, which already exists"));
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        assert_eq!(
+            git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]),
+            revisions
+        );
+        assert_eq!(git(&blog.config.blog_dir, &["status", "--porcelain"]), "");
+        assert!(!blog.config.blog_build_dir.join("index.html").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn copy_recursive_preserves_modes_and_mtimes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let blog = blog("").await;
+        let from = blog.root.join("from");
+        std::fs::create_dir_all(from.join("hooks")).unwrap();
+        std::fs::write(from.join("hooks/deploy.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::write(from.join("private.txt"), "private\n").unwrap();
+        let mode = |path: &Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        mode(&from.join("hooks/deploy.sh"), 0o755);
+        mode(&from.join("private.txt"), 0o640);
+        mode(&from.join("hooks"), 0o750);
+        let modified = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::open(from.join("private.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let to = blog.root.join("to");
+        copy_recursive(&from, &to, true).await.unwrap();
+
+        let mode = |path: &str| {
+            std::fs::metadata(to.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode("hooks/deploy.sh"), 0o755);
+        assert_eq!(mode("private.txt"), 0o640);
+        assert_eq!(mode("hooks"), 0o750);
+        assert_eq!(
+            std::fs::metadata(to.join("private.txt"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_paths_edit_their_index() {
+        let blog = blog(r#"directory_index = "index.md""#).await;
+        std::fs::create_dir_all(blog.path("posts")).unwrap();
+        std::fs::write(blog.path("posts/index.md"), "all posts\n").unwrap();
+        std::fs::create_dir_all(blog.path("empty")).unwrap();
+
+        let present = blog.reply(warp::test::request().path("/edit/posts/")).await;
+        assert_eq!(present.status(), StatusCode::OK);
+        assert!(body(&present).contains("all posts"));
+        let response = blog
+            .reply(post_form("/edit/posts/", &[("content", "some posts\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("posts/index.md"), "some posts\n");
+
+        let absent = blog.reply(warp::test::request().path("/edit/empty/")).await;
+        assert_eq!(absent.status(), StatusCode::BAD_REQUEST);
+        assert!(body(&absent).contains("is a directory without index.md"));
+    }
+
+    #[tokio::test]
+    async fn missing_files_render_the_new_file_page() {
+        let blog = blog("").await;
+
+        let response = blog.reply(warp::test::request().path("/edit/new.md")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(&response).contains(r#"id="new_file_notice""#));
+        assert!(!blog.path("new.md").exists());
+
+        let response = blog
+            .reply(post_form("/edit/new.md", &[("content", "new\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("new.md"), "new\n");
+
+        let response = blog
+            .reply(warp::test::request().path("/edit/nowhere/new.md"))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn file_encoding_round_trips_non_utf8_content() {
+        let blog = blog(r#"file_encoding = "latin1""#).await;
+        std::fs::write(blog.path("a.md"), b"caf\xe9\n").unwrap();
+
+        let response = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(&response).contains("café"));
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "naïve café\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(
+            std::fs::read(blog.path("a.md")).unwrap(),
+            b"na\xefve caf\xe9\n"
+        );
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "日本\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            std::fs::read(blog.path("a.md")).unwrap(),
+            b"na\xefve caf\xe9\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn known_paths_with_the_wrong_method_are_405() {
+        let blog = blog("").await;
+
+        let response = blog
+            .reply(warp::test::request().method("DELETE").path("/edit/a.md"))
+            .await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, PUT");
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let response = blog.reply(warp::test::request().path("/api/batch")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "POST");
+
+        let response = blog.reply(warp::test::request().path("/nowhere")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!response.headers().contains_key(ALLOW));
+        let response = blog
+            .reply(warp::test::request().method("DELETE").path("/nowhere"))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn empty_builds_leave_dest_dir_alone() {
+        let blog = blog(r#"build_command = ["true"]"#).await;
+        let published = blog.config.dest_dir.join("index.html");
+        std::fs::write(&published, "published\n").unwrap();
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("is empty, not replacing"));
+        assert_eq!(std::fs::read_to_string(&published).unwrap(), "published\n");
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    #[tokio::test]
+    async fn builds_without_the_sentinel_leave_dest_dir_alone() {
+        let blog = blog(
+            r#"build_command = ["sh", "-c", "echo built > public/other.html"]
+build_sentinel = "index.html""#,
+        )
+        .await;
+        let published = blog.config.dest_dir.join("index.html");
+        std::fs::write(&published, "published\n").unwrap();
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("index.html is missing"));
+        assert_eq!(std::fs::read_to_string(&published).unwrap(), "published\n");
+    }
+
+    #[tokio::test]
+    async fn only_editable_path_prefixes_can_be_edited_or_published() {
+        let blog = blog(r#"editable_path_prefixes = ["posts"]"#).await;
+        std::fs::create_dir_all(blog.path("posts")).unwrap();
+        std::fs::write(blog.path("posts/p.md"), "post\n").unwrap();
+
+        let response = blog
+            .reply(post_form("/edit/posts/p.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("posts/p.md"), "edited\n");
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body(&response).contains("a.md isn't in an editable directory"));
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "posts/new.md"), ("content", "new\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        // a prefix of the name isn't a prefix of the path
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "postscript.md"), ("content", "new\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!blog.path("postscript.md").exists());
+    }
+
+    #[tokio::test]
+    async fn git_lock_contention_is_retried() {
+        let blog = blog(
+            r#"git_lock_attempts = 5
+git_lock_backoff_ms = 100"#,
+        )
+        .await;
+        let lock = blog.path(".git/index.lock");
+        std::fs::write(&lock, "").unwrap();
+        // like a git gc that finishes while the edit is backing off
+        let unlock = tokio::spawn({
+            let lock = lock.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                std::fs::remove_file(lock).unwrap();
+            }
+        });
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        unlock.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(
+            git(&blog.config.blog_dir, &["show", "HEAD:a.md"]),
+            "edited\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn git_lock_contention_gives_up_eventually() {
+        let blog = blog(
+            r#"git_lock_attempts = 2
+git_lock_backoff_ms = 10"#,
+        )
+        .await;
+        std::fs::write(blog.path(".git/index.lock"), "").unwrap();
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "edited\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body(&response).contains("locked by another process, gave up after 2 attempts"));
+    }
+
+    #[tokio::test]
+    async fn require_note_rejects_edits_without_one() {
+        let blog = blog("require_note = true").await;
+        let revisions = git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]);
+
+        let edit = blog.reply(warp::test::request().path("/edit/a.md")).await;
+        assert!(body(&edit).contains(r#"placeholder="revision note" required"#));
+
+        for form in [
+            &[("content", "edited\n")][..],
+            &[("content", "edited\n"), ("note", "  ")],
+        ] {
+            let response = blog.reply(post_form("/edit/a.md", form)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(body(&response).contains("a revision note is required"));
+        }
+        let response = blog
+            .reply(post_form(
+                "/publish",
+                &[("filename", "b.md"), ("content", "b\n")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!blog.path("b.md").exists());
+        assert_eq!(blog.read("a.md"), "hello\n");
+        assert_eq!(
+            git(&blog.config.blog_dir, &["rev-list", "--count", "HEAD"]),
+            revisions
+        );
+
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("content", "edited\n"), ("note", "typo")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "edited\n");
+    }
+
+    #[tokio::test]
+    async fn publish_redirects_handle_slashes_and_mount_prefix() {
+        for (url, mount_prefix, expected) in [
+            (
+                "http://editor.example.com/tools/",
+                "",
+                "http://editor.example.com/tools/publish/missing",
+            ),
+            (
+                "http://editor.example.com/tools",
+                "",
+                "http://editor.example.com/tools/publish/missing",
+            ),
+            (
+                "http://editor.example.com/",
+                "/admin",
+                "http://editor.example.com/admin/publish/missing",
+            ),
+            (
+                "http://editor.example.com",
+                "admin/",
+                "http://editor.example.com/admin/publish/missing",
+            ),
+        ] {
+            let blog = blog(&format!(
+                "url = \"{}\"\nmount_prefix = \"{}\"",
+                url, mount_prefix
+            ))
+            .await;
+            let path = format!("{}/edit/missing", blog.config.mount_prefix);
+            let response = blog.reply(warp::test::request().path(&path)).await;
+            assert!(
+                body(&response).contains(&format!("URL={}\"", expected)),
+                "{} {}: {}",
+                url,
+                mount_prefix,
+                body(&response)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn form_endpoints_explain_a_missing_or_json_body() {
+        let blog = blog("").await;
+
+        // with no Content-Type at all, body::form() hands over an empty form
+        for (path, missing_field) in [
+            ("/edit/a.md", "no content from form?"),
+            ("/publish", "missing filename"),
+            ("/revert", "no revision from form?"),
+        ] {
+            let missing = blog
+                .reply(warp::test::request().method("POST").path(path))
+                .await;
+            assert_eq!(missing.status(), StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(body(&missing), missing_field);
+
+            let json = blog
+                .reply(
+                    warp::test::request()
+                        .method("POST")
+                        .path(path)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(r#"{"content": "edited\n"}"#),
+                )
+                .await;
+            assert_eq!(
+                json.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{}",
+                path
+            );
+            assert!(body(&json).contains("a form body is required"));
+        }
+        assert_eq!(blog.read("a.md"), "hello\n");
+    }
+
+    const ADMIN_USERS: &str = r#"users = { admin = "admin-password", me = "my-password" }
+admins = ["admin"]"#;
+
+    #[tokio::test]
+    async fn admin_endpoints_need_an_admin() {
+        let blog = blog(ADMIN_USERS).await;
+
+        let response = blog
+            .reply(warp::test::request().path("/admin/status"))
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .path("/admin/status")
+                    .header(AUTHORIZATION, basic_auth("admin", "wrong")),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        std::fs::write(blog.path("a.md"), "uncommitted\n").unwrap();
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .method("POST")
+                    .path("/admin/discard")
+                    .header(AUTHORIZATION, basic_auth("me", "my-password")),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(blog.read("a.md"), "uncommitted\n");
+    }
+
+    #[tokio::test]
+    async fn admin_discard_resets_the_tree() {
+        let blog = blog(ADMIN_USERS).await;
+        std::fs::write(blog.path("a.md"), "uncommitted\n").unwrap();
+        std::fs::write(blog.path("new.md"), "untracked\n").unwrap();
+
+        let response = blog
+            .reply(
+                warp::test::request()
+                    .method("POST")
+                    .path("/admin/discard")
+                    .header(AUTHORIZATION, basic_auth("admin", "admin-password")),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "hello\n");
+        assert!(!blog.path("new.md").exists());
+    }
+
+    #[tokio::test]
+    async fn admin_status_shows_the_working_tree() {
+        let get_status = || {
+            warp::test::request()
+                .path("/admin/status")
+                .header(AUTHORIZATION, basic_auth("admin", "admin-password"))
+        };
+
+        let with_status = blog(&format!(
+            "status_command = [\"git\", \"status\", \"--porcelain\"]\n{}",
+            ADMIN_USERS
+        ))
+        .await;
+        let response = with_status.reply(get_status()).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(body(&response).contains("working tree is clean"));
+        std::fs::write(with_status.path("a.md"), "uncommitted\n").unwrap();
+        let response = with_status.reply(get_status()).await;
+        assert!(body(&response).contains(" M a.md"));
+
+        let without_status = blog(ADMIN_USERS).await;
+        let response = without_status.reply(get_status()).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(body(&response).contains("no status_command configured"));
+    }
+
+    #[tokio::test]
+    async fn search_skips_files_that_cant_be_edited() {
+        let blog = blog(
+            r#"protected_paths = ["posts/secret.md"]
+editable_path_prefixes = ["posts"]"#,
+        )
+        .await;
+        std::fs::create_dir_all(blog.path("posts")).unwrap();
+        std::fs::write(blog.path("posts/p.md"), "hello post\n").unwrap();
+        std::fs::write(blog.path("posts/secret.md"), "hello secret\n").unwrap();
+        std::fs::write(blog.path("posts/config.toml"), "# hello config\n").unwrap();
+
+        let response = blog
+            .reply(warp::test::request().path("/search?q=hello"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = body(&response);
+        assert!(page.contains("p.md"), "{}", page);
+        assert!(!page.contains("a.md"));
+        assert!(!page.contains("secret"));
+        assert!(!page.contains("config"));
+    }
+
+    const BRANCH_COMMANDS: &str = r#"branch_command = ["git", "branch", "--show-current"]
+checkout_command = ["git", "checkout", "-q"]
+promote_command = ["git", "merge", "-q", "--ff-only"]"#;
+
+    #[tokio::test]
+    async fn roles_decide_who_can_write() {
+        let blog = blog(
+            r#"users = { reader = "reader-password", editor = "editor-password", publisher = "publisher-password" }
+roles = { editor = "editor", publisher = "publisher" }"#,
+        )
+        .await;
+        let as_user = |request: warp::test::RequestBuilder, user: &str| {
+            request.header(
+                AUTHORIZATION,
+                basic_auth(user, &format!("{}-password", user)),
+            )
+        };
+        let edit = || post_form("/edit/a.md", &[("content", "edited\n")]);
+        let delete = || post_form("/edit/a.md", &[("content", ""), ("delete", "on")]);
+
+        let response = blog.reply(edit()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = blog.reply(as_user(edit(), "reader")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            body(&response),
+            "reader can't edit, that takes the editor role"
+        );
+        let response = blog
+            .reply(as_user(warp::test::request().path("/edit/a.md"), "reader"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let response = blog.reply(as_user(edit(), "editor")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "edited\n");
+        let response = blog.reply(as_user(delete(), "editor")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(blog.path("a.md").exists());
+
+        let response = blog.reply(as_user(delete(), "publisher")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(!blog.path("a.md").exists());
+    }
+
+    #[tokio::test]
+    async fn edit_locks_warn_other_sessions_until_an_admin_releases_them() {
+        let blog = blog(&format!(
+            "{}\nroles = {{ me = \"editor\", admin = \"editor\" }}",
+            ADMIN_USERS
+        ))
+        .await;
+        let get_edit = |session: &str| {
+            warp::test::request()
+                .path("/edit/a.md")
+                .header(AUTHORIZATION, basic_auth("me", "my-password"))
+                .header("cookie", format!("editor_session={}", session))
+        };
+        let unlock = |user: &str, password: &str| {
+            post_form("/edit/unlock", &[("path", "/edit/a.md")])
+                .header(AUTHORIZATION, basic_auth(user, password))
+                .header("cookie", "editor_session=mine")
+        };
+
+        let response = blog.reply(get_edit("theirs")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!body(&response).contains("lock_warning"));
+        assert!(blog.state.is_locked(&blog.path("a.md")));
+        let response = blog.reply(get_edit("mine")).await;
+        assert!(body(&response).contains("someone else is editing this file"));
+
+        let response = blog.reply(unlock("me", "my-password")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body(&response).contains("only an admin can release it"));
+        assert!(blog.state.is_locked(&blog.path("a.md")));
+
+        let response = blog.reply(unlock("admin", "admin-password")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert!(!blog.state.is_locked(&blog.path("a.md")));
+        let response = blog.reply(get_edit("mine")).await;
+        assert!(!body(&response).contains("lock_warning"));
+        let response = blog.reply(get_edit("theirs")).await;
+        assert!(body(&response).contains("someone else is editing this file"));
+    }
+
+    #[tokio::test]
+    async fn branch_edits_are_written_on_the_branch_and_promoted() {
+        let blog = blog(&format!(
+            "{}\n{}\nroles = {{ me = \"publisher\" }}",
+            BRANCH_COMMANDS, ADMIN_USERS
+        ))
+        .await;
+        let dir = &blog.config.blog_dir;
+        let current = git(dir, &["branch", "--show-current"]);
+        let current = current.trim();
+        git(dir, &["checkout", "-q", "-b", "staging"]);
+        std::fs::write(blog.path("a.md"), "staged\n").unwrap();
+        git(dir, &["commit", "-q", "-am", "staged"]);
+        git(dir, &["checkout", "-q", current]);
+
+        // a.md differs between the branches, so it can't be written before checking out
+        let response = blog
+            .reply(
+                post_form(
+                    "/edit/a.md",
+                    &[("content", "edited\n"), ("branch", "staging")],
+                )
+                .header(AUTHORIZATION, basic_auth("me", "my-password")),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(git(dir, &["show", "staging:a.md"]), "edited\n");
+        assert_eq!(git(dir, &["branch", "--show-current"]).trim(), current);
+        assert_eq!(blog.read("a.md"), "hello\n");
+        assert_eq!(git(dir, &["status", "--porcelain"]), "");
+        assert!(!blog.config.dest_dir.join("index.html").exists());
+
+        let promote = |authorization| {
+            post_form("/admin/promote", &[("branch", "staging")])
+                .header(AUTHORIZATION, authorization)
+        };
+        let response = blog.reply(promote(basic_auth("me", "my-password"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let response = blog
+            .reply(promote(basic_auth("admin", "admin-password")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(blog.read("a.md"), "edited\n");
+        assert_eq!(
+            std::fs::read_to_string(blog.config.dest_dir.join("index.html")).unwrap(),
+            "built\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn skipped_builds_dont_touch_after_edit() {
+        let blog = blog(
+            r#"allow_skip_build = true
+touch_after_edit = ["config.toml"]"#,
+        )
+        .await;
+        std::fs::write(blog.path("config.toml"), "").unwrap();
+        let long_ago = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let modified = || {
+            std::fs::metadata(blog.path("config.toml"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        std::fs::File::open(blog.path("config.toml"))
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+
+        let response = blog
+            .reply(post_form(
+                "/edit/a.md",
+                &[("content", "skipped\n"), ("skip_build", "on")],
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_eq!(modified(), long_ago);
+
+        let response = blog
+            .reply(post_form("/edit/a.md", &[("content", "built\n")]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        assert_ne!(modified(), long_ago);
+    }
+
+    #[tokio::test]
+    async fn writes_are_refused_after_a_failed_reset_until_cleared() {
+        let blog = blog(&format!(
+            "create_revision = [\"false\"]\nreset_command = [\"false\"]\n{}\nroles = {{ admin = \"publisher\" }}",
+            ADMIN_USERS
+        ))
+        .await;
+        let admin = || basic_auth("admin", "admin-password");
+
+        let response = blog
+            .reply(
+                post_form("/edit/a.md", &[("content", "edited\n")]).header(AUTHORIZATION, admin()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(&response).contains("failed resetting"));
+
+        let health = blog.reply(warp::test::request().path("/health")).await;
+        assert_eq!(health.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let status = blog
+            .reply(
+                warp::test::request()
+                    .path("/admin/status")
+                    .header(AUTHORIZATION, admin()),
+            )
+            .await;
+        assert!(body(&status).contains("reset failed"));
+
+        // fixed by hand
+        git(&blog.config.blog_dir, &["checkout", "HEAD", "--", "a.md"]);
+        let response = blog
+            .reply(
+                post_form("/edit/a.md", &[("content", "edited\n")]).header(AUTHORIZATION, admin()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body(&response).contains("manual intervention required"));
+        assert_eq!(blog.read("a.md"), "hello\n");
+
+        let clear = || {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/clear-degraded")
+                .header(AUTHORIZATION, admin())
+        };
+        let response = blog.reply(clear()).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", body(&response));
+        let response = blog.reply(clear()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let health = blog.reply(warp::test::request().path("/health")).await;
+        assert_eq!(health.status(), StatusCode::OK);
+
+        // the next write gets as far as the broken create_revision again
+        let response = blog
+            .reply(
+                post_form("/edit/a.md", &[("content", "edited\n")]).header(AUTHORIZATION, admin()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body(&response).contains("manual intervention required"));
+    }
 }